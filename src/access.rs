@@ -0,0 +1,138 @@
+//! Source IP allowlisting and rate limiting for the ingest endpoint.
+
+use crate::config::AccessConfig;
+use crate::metrics;
+use crate::ratelimit::RateLimiter;
+use crate::types::AppState;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_prometheus::metrics::counter;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use tracing::warn;
+
+/// The client's address, after skipping any trusted proxies.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+#[derive(Default)]
+pub struct Access {
+    allow: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+    limiter: Option<RateLimiter>,
+}
+
+impl Access {
+    pub fn new(config: &AccessConfig) -> Self {
+        Self {
+            allow: config.allow.clone(),
+            trusted_proxies: config.trusted_proxies.clone(),
+            limiter: config.rate_limit.as_ref().map(RateLimiter::new),
+        }
+    }
+
+    /// Find the client's address: the peer, unless it's a trusted proxy, in
+    /// which case the right-most untrusted `X-Forwarded-For` entry.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer;
+        if !self.is_trusted(client) {
+            return client;
+        }
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect::<Vec<_>>();
+        for entry in forwarded.into_iter().rev() {
+            let Ok(ip) = entry.trim().parse::<IpAddr>() else {
+                break;
+            };
+            client = ip;
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        client
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Resolve the [ClientIp] and reject clients that aren't allowed, or are over
+/// their rate limit.
+pub async fn check_access(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = connect_info.map_or(IpAddr::from([0, 0, 0, 0]), |info| info.0.ip());
+    let ip = state.access.client_ip(peer, request.headers());
+
+    if !state.access.is_allowed(ip) {
+        warn!(%ip, "rejected request from address not in allowlist");
+        counter!(metrics::name("recv_rejected_requests"), "reason" => "not_allowed").increment(1);
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    if let Some(limiter) = &state.access.limiter {
+        if !limiter.check(ip) {
+            warn!(%ip, "rate limited request");
+            counter!(metrics::name("recv_rejected_requests"), "reason" => "rate_limited")
+                .increment(1);
+            return StatusCode::TOO_MANY_REQUESTS.into_response();
+        }
+    }
+
+    request.extensions_mut().insert(ClientIp(ip));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use anyhow::Result;
+
+    #[test]
+    fn resolves_client_ip_through_trusted_proxies() -> Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [access]
+            trusted_proxies = ["10.0.0.0/8", "fd00::/8"]
+            "#,
+        )?;
+        let access = Access::new(&config.access);
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", "198.51.100.7, 203.0.113.9".parse()?);
+        headers.append("x-forwarded-for", "10.1.1.1".parse()?);
+
+        let proxy = IpAddr::from([10, 0, 0, 1]);
+        let stranger = IpAddr::from([192, 0, 2, 1]);
+        // A spoofed left-most entry is never reached.
+        assert_eq!(
+            access.client_ip(proxy, &headers),
+            IpAddr::from([203, 0, 113, 9])
+        );
+        // Only trusted proxies get to set the header.
+        assert_eq!(access.client_ip(stranger, &headers), stranger);
+        assert_eq!(access.client_ip(proxy, &HeaderMap::new()), proxy);
+
+        headers.insert("x-forwarded-for", "not-an-ip, 10.2.2.2".parse()?);
+        assert_eq!(
+            access.client_ip(proxy, &headers),
+            IpAddr::from([10, 2, 2, 2])
+        );
+        Ok(())
+    }
+}
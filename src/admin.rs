@@ -0,0 +1,378 @@
+//! Operational endpoints, guarded by a bearer token.
+
+use crate::controller::{Command, DriverAction};
+use crate::health::Health;
+use crate::quarantine::Quarantine;
+use crate::reload::Reloader;
+use axum::{
+    extract::{Path, Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::{reload, Registry};
+
+/// Swaps the level of the installed `tracing` subscriber.
+pub type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// How long a driver action may wait for the controller, which only handles
+/// commands between messages.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub struct AdminState {
+    token: Arc<str>,
+    reloader: Arc<Reloader>,
+    quarantine: Arc<Quarantine>,
+    health: Arc<Health>,
+    commands: Option<mpsc::UnboundedSender<Command>>,
+    log_level: Option<LogLevelHandle>,
+}
+
+impl AdminState {
+    pub fn new(token: &str, reloader: Arc<Reloader>) -> Self {
+        Self {
+            token: token.into(),
+            reloader,
+            quarantine: Arc::default(),
+            health: Arc::default(),
+            commands: None,
+            log_level: None,
+        }
+    }
+
+    pub fn with_quarantine(mut self, quarantine: Arc<Quarantine>) -> Self {
+        self.quarantine = quarantine;
+        self
+    }
+
+    pub fn with_health(mut self, health: Arc<Health>) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Enables pausing, resuming and flushing drivers.
+    pub fn with_commands(mut self, commands: mpsc::UnboundedSender<Command>) -> Self {
+        self.commands = Some(commands);
+        self
+    }
+
+    /// Enables changing the log level.
+    pub fn with_log_level(mut self, log_level: LogLevelHandle) -> Self {
+        self.log_level = Some(log_level);
+        self
+    }
+}
+
+pub fn create_admin_app(state: AdminState) -> axum::Router {
+    axum::Router::new()
+        .route("/admin/reload", post(reload))
+        .route("/admin/quarantine", get(quarantine))
+        .route("/admin/drivers", get(drivers))
+        .route("/admin/drivers/:name/pause", post(pause))
+        .route("/admin/drivers/:name/resume", post(resume))
+        .route("/admin/drivers/:name/flush", post(flush))
+        .route("/admin/errors", get(errors))
+        .route("/admin/log-level", get(log_level).put(set_log_level))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .with_state(state)
+}
+
+async fn require_token(
+    State(state): State<AdminState>,
+    auth: Option<TypedHeader<Authorization<Bearer>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorized = auth.is_some_and(|TypedHeader(auth)| {
+        ring::constant_time::verify_slices_are_equal(
+            auth.token().as_bytes(),
+            state.token.as_bytes(),
+        )
+        .is_ok()
+    });
+    if !authorized {
+        warn!(uri = ?request.uri(), "rejected unauthorized admin request");
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+async fn reload(State(state): State<AdminState>) -> impl IntoResponse {
+    match state.reloader.reload().await {
+        Ok(()) => (StatusCode::OK, String::from("configuration reloaded")),
+        Err(e) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("rejected configuration reload: {e:#}"),
+        ),
+    }
+}
+
+/// The most recent payload elements that failed to parse.
+async fn quarantine(State(state): State<AdminState>) -> impl IntoResponse {
+    Json(state.quarantine.entries())
+}
+
+/// Each driver's health and counters, as also reported by `/ready`.
+async fn drivers(State(state): State<AdminState>) -> impl IntoResponse {
+    Json(state.health.drivers())
+}
+
+/// The most recent errors returned by drivers, oldest first.
+async fn errors(State(state): State<AdminState>) -> impl IntoResponse {
+    Json(state.health.errors())
+}
+
+async fn pause(state: State<AdminState>, name: Path<String>) -> Response {
+    driver_action(state, name, DriverAction::Pause).await
+}
+
+async fn resume(state: State<AdminState>, name: Path<String>) -> Response {
+    driver_action(state, name, DriverAction::Resume).await
+}
+
+async fn flush(state: State<AdminState>, name: Path<String>) -> Response {
+    driver_action(state, name, DriverAction::Flush).await
+}
+
+#[derive(Serialize)]
+struct DriverActionResponse {
+    sent: usize,
+}
+
+async fn driver_action(
+    State(state): State<AdminState>,
+    Path(driver): Path<String>,
+    action: DriverAction,
+) -> Response {
+    let Some(commands) = &state.commands else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let (reply, result) = oneshot::channel();
+    let command = Command::Driver {
+        driver,
+        action,
+        reply,
+    };
+    if commands.send(command).is_err() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "controller is not running").into_response();
+    }
+    match tokio::time::timeout(COMMAND_TIMEOUT, result).await {
+        Ok(Ok(Ok(sent))) => Json(DriverActionResponse { sent }).into_response(),
+        Ok(Ok(Err(e))) => (StatusCode::CONFLICT, format!("{e:#}")).into_response(),
+        Ok(Err(_)) => {
+            (StatusCode::SERVICE_UNAVAILABLE, "controller is not running").into_response()
+        }
+        Err(_) => (
+            StatusCode::GATEWAY_TIMEOUT,
+            "timed out waiting for the controller",
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogLevel {
+    level: String,
+}
+
+async fn log_level(State(state): State<AdminState>) -> Response {
+    let Some(handle) = &state.log_level else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match handle.clone_current() {
+        Some(level) => Json(LogLevel {
+            level: level.to_string().to_lowercase(),
+        })
+        .into_response(),
+        None => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+/// Change the log level until the next restart, e.g. `{"level": "debug"}`.
+async fn set_log_level(
+    State(state): State<AdminState>,
+    Json(LogLevel { level }): Json<LogLevel>,
+) -> Response {
+    let Some(handle) = &state.log_level else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Ok(filter) = level.parse::<LevelFilter>() else {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("unknown log level `{level}`"),
+        )
+            .into_response();
+    };
+    if let Err(e) = handle.reload(filter) {
+        return (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response();
+    }
+    info!(%filter, "changed log level");
+    Json(LogLevel {
+        level: filter.to_string().to_lowercase(),
+    })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::controller::Command;
+    use anyhow::{anyhow, Result};
+    use axum::body::Body;
+    use tokio::sync::mpsc;
+    use tower::Service;
+
+    fn request(token: Option<&str>) -> Result<Request> {
+        let mut builder = Request::builder().method("POST").uri("/admin/reload");
+        if let Some(token) = token {
+            builder = builder.header("authorization", format!("Bearer {token}"));
+        }
+        Ok(builder.body(Body::empty())?)
+    }
+
+    #[tokio::test]
+    async fn reload_requires_token() -> Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Command>();
+        let reloader = Reloader::new(Box::new(|| Ok(Config::default())), tx);
+        let mut app = create_admin_app(AdminState::new("hunter2", Arc::new(reloader)));
+        let mut app_service = app.as_service();
+
+        for token in [None, Some("hunter3")] {
+            let response = app_service.call(request(token)?).await?;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{token:?}");
+        }
+        assert!(rx.try_recv().is_err());
+
+        let response = app_service.call(request(Some("hunter2"))?).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(matches!(rx.try_recv(), Ok(Command::Reconfigure { .. })));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_config_is_rejected() -> Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Command>();
+        let reloader = Reloader::new(Box::new(|| Err(anyhow!("bad config"))), tx);
+        let mut app = create_admin_app(AdminState::new("hunter2", Arc::new(reloader)));
+
+        let response = app.as_service().call(request(Some("hunter2"))?).await?;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(rx.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sends_driver_actions_to_controller() -> Result<()> {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Command>();
+        let reloader = Reloader::new(Box::new(|| Ok(Config::default())), tx.clone());
+        let mut app =
+            create_admin_app(AdminState::new("hunter2", Arc::new(reloader)).with_commands(tx));
+        tokio::spawn(async move {
+            while let Some(command) = rx.recv().await {
+                if let Command::Driver { driver, reply, .. } = command {
+                    let result = match driver.as_str() {
+                        "loki" => Ok(3),
+                        _ => Err(anyhow!("no driver named `{driver}`")),
+                    };
+                    let _ = reply.send(result);
+                }
+            }
+        });
+
+        let request = |uri: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("authorization", "Bearer hunter2")
+                .body(Body::empty())
+        };
+        let response = app
+            .as_service()
+            .call(request("/admin/drivers/loki/flush")?)
+            .await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(&body[..], br#"{"sent":3}"#);
+
+        let response = app
+            .as_service()
+            .call(request("/admin/drivers/nope/pause")?)
+            .await?;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn changes_log_level() -> Result<()> {
+        let (tx, _rx) = mpsc::unbounded_channel::<Command>();
+        let reloader = Reloader::new(Box::new(|| Ok(Config::default())), tx);
+        let (_layer, handle) = reload::Layer::<_, Registry>::new(LevelFilter::INFO);
+        let mut app = create_admin_app(
+            AdminState::new("hunter2", Arc::new(reloader)).with_log_level(handle.clone()),
+        );
+
+        let request = |level: &str| {
+            Request::builder()
+                .method("PUT")
+                .uri("/admin/log-level")
+                .header("authorization", "Bearer hunter2")
+                .header("content-type", "application/json")
+                .body(Body::from(format!(r#"{{"level": "{level}"}}"#)))
+        };
+        let response = app.as_service().call(request("debug")?).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(handle.clone_current(), Some(LevelFilter::DEBUG));
+
+        let response = app.as_service().call(request("loud")?).await?;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let request = Request::builder()
+            .uri("/admin/log-level")
+            .header("authorization", "Bearer hunter2")
+            .body(Body::empty())?;
+        let response = app.as_service().call(request).await?;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        assert_eq!(&body[..], br#"{"level":"debug"}"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lists_quarantined_messages() -> Result<()> {
+        let (tx, _rx) = mpsc::unbounded_channel::<Command>();
+        let reloader = Reloader::new(Box::new(|| Ok(Config::default())), tx);
+        let quarantine = Arc::new(Quarantine::default());
+        let (_, rejected) = crate::types::VercelPayload::from_json(r#"[{"id": 1}]"#)?;
+        rejected
+            .into_iter()
+            .for_each(|rejected| quarantine.push(rejected));
+        let mut app = create_admin_app(
+            AdminState::new("hunter2", Arc::new(reloader)).with_quarantine(quarantine),
+        );
+
+        let request = Request::builder()
+            .uri("/admin/quarantine")
+            .header("authorization", "Bearer hunter2")
+            .body(Body::empty())?;
+        let response = app.as_service().call(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        let entries: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(entries[0]["position"], 0);
+        assert_eq!(entries[0]["raw"], r#"{"id": 1}"#);
+        Ok(())
+    }
+}
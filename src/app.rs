@@ -1,16 +1,39 @@
-use crate::{handlers, types};
+use crate::health::Health;
+use crate::{access, handlers, types};
+use axum::middleware;
+use std::sync::Arc;
 
 pub fn create_app(state: types::AppState) -> axum::Router {
+    let health = state.health.clone();
+    create_ingest_app(state).merge(create_ops_app(health))
+}
+
+/// The public endpoint Vercel sends logs to.
+pub fn create_ingest_app(state: types::AppState) -> axum::Router {
+    axum::Router::new()
+        .route(
+            "/vercel",
+            axum::routing::post(handlers::ingest).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                access::check_access,
+            )),
+        )
+        .with_state(state)
+}
+
+/// Endpoints for the operator, which can be served on a separate listener.
+pub fn create_ops_app(health: Arc<Health>) -> axum::Router {
     axum::Router::new()
         .route("/", axum::routing::post(handlers::root))
         .route("/health", axum::routing::get(handlers::health_check))
-        .route("/vercel", axum::routing::post(handlers::ingest))
-        .with_state(state)
+        .route("/ready", axum::routing::get(handlers::ready))
+        .with_state(health)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::ReadinessConfig;
     use anyhow::Result;
     use axum::{
         body::Body,
@@ -31,6 +54,63 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn split_ingest_and_ops_apps() -> Result<()> {
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+        let state = types::AppState::new("test", b"", tx)?;
+        let mut ingest = create_ingest_app(state);
+        let mut ops = create_ops_app(Arc::default());
+
+        let health = || Request::builder().uri("/health").body(Body::empty());
+        let response = ingest.as_service().call(health()?).await?;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = ops.as_service().call(health()?).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let vercel = Request::builder()
+            .method("POST")
+            .uri("/vercel")
+            .body(Body::empty())?;
+        let response = ingest.as_service().call(vercel).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ready_check() -> Result<()> {
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+        let health = Arc::new(Health::new(ReadinessConfig {
+            max_queue_depth: Some(0),
+            max_consecutive_failures: None,
+        }));
+        let state = types::AppState::new("test", b"deadbeef1234dacb4321", tx)?.with_health(health);
+        let mut app = create_app(state.clone());
+
+        let ready = || Request::builder().uri("/ready").body(Body::empty());
+        let response = app.as_service().call(ready()?).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Nothing drains the queue, so the message stays in it.
+        let body = include_str!("fixtures/sample_1.json");
+        let request = Request::builder()
+            .method("POST")
+            .uri("/vercel")
+            .header(
+                "x-vercel-signature",
+                state.sign_request_for_test_only(body.as_bytes()),
+            )
+            .body(Body::from(body))?;
+        app.as_service().call(request).await?;
+        let response = app.as_service().call(ready()?).await?;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        let readiness: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(readiness["ready"], false);
+        assert_eq!(readiness["queueDepth"], 1);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn root_check() -> Result<()> {
         let _ = tracing_subscriber::fmt().json().try_init();
@@ -132,6 +212,223 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn ingest_check_ndjson() -> Result<()> {
+        let _ = tracing_subscriber::fmt().json().try_init();
+        let messages: Vec<serde_json::Value> =
+            serde_json::from_str(include_str!("fixtures/sample_2.json"))?;
+        let mut lines: Vec<String> = messages.iter().map(|m| m.to_string()).collect();
+        lines.insert(1, String::from("{\"not\": \"a message\"}"));
+        let data = lines.join("\n");
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+
+        let state = types::AppState::new("test", b"deadbeef1234dacb4321", tx)?;
+        let mut app = create_app(state.clone());
+        let mut app_service = app.as_service();
+
+        for content_type in [Some("application/x-ndjson"), None] {
+            let mut builder = Request::builder()
+                .method("POST")
+                .header(
+                    "x-vercel-signature",
+                    state.sign_request_for_test_only(data.as_bytes()),
+                )
+                .uri("/vercel");
+            if let Some(content_type) = content_type {
+                builder = builder.header("content-type", content_type);
+            }
+            let response = app_service
+                .call(builder.body(Body::from(data.clone()))?)
+                .await?;
+            assert_eq!(response.status(), StatusCode::OK, "{content_type:?}");
+        }
+        assert_eq!(rx.len(), 6);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ingest_check_partial_json() -> Result<()> {
+        let _ = tracing_subscriber::fmt().json().try_init();
+        let mut messages: Vec<serde_json::Value> =
+            serde_json::from_str(include_str!("fixtures/sample_2.json"))?;
+        messages.insert(2, serde_json::json!({ "id": "missing-everything" }));
+        let data = serde_json::to_string(&messages)?;
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+
+        let state = types::AppState::new("test", b"deadbeef1234dacb4321", tx)?;
+        let mut app = create_app(state.clone());
+        let mut app_service = app.as_service();
+
+        let request = Request::builder()
+            .method("POST")
+            .header(
+                "x-vercel-signature",
+                state.sign_request_for_test_only(data.as_bytes()),
+            )
+            .uri("/vercel")
+            .body(Body::from(data))?;
+        let response = app_service.call(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(rx.len(), 3);
+
+        let quarantined = state.quarantine.entries();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].position, 2);
+        assert!(quarantined[0].raw.contains("missing-everything"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ingest_check_compressed() -> Result<()> {
+        use std::io::Write;
+
+        let _ = tracing_subscriber::fmt().json().try_init();
+        let data = include_bytes!("fixtures/sample_2.json");
+        let gzip = {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        };
+        let deflate = {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        };
+        let zstd = zstd::encode_all(&data[..], 0)?;
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+
+        let state = types::AppState::new("test", b"deadbeef1234dacb4321", tx)?;
+        let mut app = create_app(state.clone());
+        let mut app_service = app.as_service();
+
+        // Signed over the compressed bytes, as sent on the wire, or over the
+        // decompressed payload.
+        let cases = [
+            ("gzip", &gzip, state.sign_request_for_test_only(&gzip)),
+            ("deflate", &deflate, state.sign_request_for_test_only(data)),
+            ("zstd", &zstd, state.sign_request_for_test_only(data)),
+        ];
+        for (encoding, body, signature) in cases {
+            let request = Request::builder()
+                .method("POST")
+                .header("x-vercel-signature", signature)
+                .header("content-encoding", encoding)
+                .uri("/vercel")
+                .body(Body::from(body.clone()))?;
+            let response = app_service.call(request).await?;
+            assert_eq!(response.status(), StatusCode::OK, "{encoding}");
+        }
+        assert_eq!(rx.len(), 9);
+
+        // Neither the compressed nor the decompressed bytes were signed.
+        let request = Request::builder()
+            .method("POST")
+            .header(
+                "x-vercel-signature",
+                state.sign_request_for_test_only(b"[]"),
+            )
+            .header("content-encoding", "gzip")
+            .uri("/vercel")
+            .body(Body::from(gzip.clone()))?;
+        let response = app_service.call(request).await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = Request::builder()
+            .method("POST")
+            .header(
+                "x-vercel-signature",
+                state.sign_request_for_test_only(&gzip),
+            )
+            .header("content-encoding", "br")
+            .uri("/vercel")
+            .body(Body::from(gzip.clone()))?;
+        let response = app_service.call(request).await?;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let request = Request::builder()
+            .method("POST")
+            .header("x-vercel-signature", state.sign_request_for_test_only(data))
+            .header("content-encoding", "gzip")
+            .uri("/vercel")
+            .body(Body::from(&data[..]))?;
+        let response = app_service.call(request).await?;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ingest_check_decompression_limit() -> Result<()> {
+        let _ = tracing_subscriber::fmt().json().try_init();
+        // Highly compressible, so tiny on the wire.
+        let bomb = zstd::encode_all(&vec![b' '; 1024 * 1024][..], 19)?;
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+
+        let state = types::AppState::new("test", b"deadbeef1234dacb4321", tx)?
+            .with_max_decompressed_bytes(64 * 1024);
+        let mut app = create_app(state.clone());
+        let mut app_service = app.as_service();
+
+        let request = Request::builder()
+            .method("POST")
+            .header(
+                "x-vercel-signature",
+                state.sign_request_for_test_only(&bomb),
+            )
+            .header("content-encoding", "zstd")
+            .uri("/vercel")
+            .body(Body::from(bomb))?;
+        let response = app_service.call(request).await?;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(rx.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ingest_check_unverified_decompression_limit() -> Result<()> {
+        let _ = tracing_subscriber::fmt().json().try_init();
+        let data = include_bytes!("fixtures/sample_2.json");
+        let zstd = zstd::encode_all(&data[..], 0)?;
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+
+        // Signed over the decompressed payload, which is bigger than may be
+        // decompressed before verifying it.
+        let state = types::AppState::new("test", b"deadbeef1234dacb4321", tx)?
+            .with_max_unverified_decompressed_bytes(64);
+        let mut app = create_app(state.clone());
+        let mut app_service = app.as_service();
+
+        let request = Request::builder()
+            .method("POST")
+            .header("x-vercel-signature", state.sign_request_for_test_only(data))
+            .header("content-encoding", "zstd")
+            .uri("/vercel")
+            .body(Body::from(zstd.clone()))?;
+        let response = app_service.call(request).await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Signed over the bytes as sent, it's only limited once verified.
+        let request = Request::builder()
+            .method("POST")
+            .header(
+                "x-vercel-signature",
+                state.sign_request_for_test_only(&zstd),
+            )
+            .header("content-encoding", "zstd")
+            .uri("/vercel")
+            .body(Body::from(zstd))?;
+        let response = app_service.call(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(rx.len(), 3);
+        Ok(())
+    }
+
     /// Test Vercel's verification step.
     ///
     /// That doesn't sign the incoming request, but expects a HTTP 200 OK
@@ -186,6 +483,234 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn ingest_check_signature_algorithms() -> Result<()> {
+        use crate::config::SignatureAlgorithm;
+
+        let _ = tracing_subscriber::fmt().json().try_init();
+        let data = include_bytes!("fixtures/sample_2.json");
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+
+        let state = types::AppState::new("test", b"deadbeef1234dacb4321", tx)?;
+        let sha1 = state.sign_request_with_for_test_only(SignatureAlgorithm::Sha1, data);
+        let sha256 = state.sign_request_with_for_test_only(SignatureAlgorithm::Sha256, data);
+        let cases = [
+            (SignatureAlgorithm::Auto, sha1.clone(), StatusCode::OK),
+            (SignatureAlgorithm::Auto, sha256.clone(), StatusCode::OK),
+            (
+                SignatureAlgorithm::Auto,
+                format!("sha256={sha256}"),
+                StatusCode::OK,
+            ),
+            (
+                SignatureAlgorithm::Auto,
+                format!("sha1={sha256}"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (SignatureAlgorithm::Sha1, sha1.clone(), StatusCode::OK),
+            (
+                SignatureAlgorithm::Sha1,
+                sha256.clone(),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (SignatureAlgorithm::Sha256, sha256.clone(), StatusCode::OK),
+            (
+                SignatureAlgorithm::Sha256,
+                sha1.clone(),
+                StatusCode::UNAUTHORIZED,
+            ),
+        ];
+
+        let mut accepted = 0;
+        for (algorithm, signature, expected) in cases {
+            let mut app = create_app(state.clone().with_signature_algorithm(algorithm));
+            let request = Request::builder()
+                .method("POST")
+                .header("x-vercel-signature", &signature)
+                .uri("/vercel")
+                .body(Body::from(&data[..]))?;
+            let response = app.as_service().call(request).await?;
+            assert_eq!(response.status(), expected, "{algorithm:?} {signature}");
+            if expected == StatusCode::OK {
+                accepted += 1;
+            }
+        }
+        assert_eq!(rx.len(), accepted * 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ingest_check_multiple_secrets() -> Result<()> {
+        let _ = tracing_subscriber::fmt().json().try_init();
+        let data = include_bytes!("fixtures/sample_2.json");
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+
+        let state = types::AppState::new("test", b"", tx.clone())?.with_secrets(vec![
+            types::VercelSecret::new("current", b"deadbeef1234dacb4321", None),
+            types::VercelSecret::new("team-b", b"4321dacbdeadbeef1234", Some(String::from("b")))
+                .with_vercel_verify("team-b-verify")?,
+        ]);
+        let mut app = create_app(state);
+        let mut app_service = app.as_service();
+
+        // Both teams' verification handshakes succeed.
+        let request = Request::builder()
+            .method("POST")
+            .uri("/vercel")
+            .body(Body::empty())?;
+        let response = app_service.call(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        let verify: Vec<_> = response
+            .headers()
+            .get_all("x-vercel-verify")
+            .iter()
+            .collect();
+        assert_eq!(verify, ["test", "team-b-verify"]);
+
+        for (secret, tenant) in [
+            (&b"deadbeef1234dacb4321"[..], None),
+            (&b"4321dacbdeadbeef1234"[..], Some("b")),
+        ] {
+            let signer = types::AppState::new("test", secret, tx.clone())?;
+            let request = Request::builder()
+                .method("POST")
+                .header(
+                    "x-vercel-signature",
+                    signer.sign_request_for_test_only(data),
+                )
+                .uri("/vercel")
+                .body(Body::from(&data[..]))?;
+            let response = app_service.call(request).await?;
+            assert_eq!(response.status(), StatusCode::OK);
+            for _ in 0..3 {
+                assert_eq!(rx.try_recv()?.tenant.as_deref(), tenant);
+            }
+        }
+
+        let stranger = types::AppState::new("test", b"not one of ours", tx)?;
+        let request = Request::builder()
+            .method("POST")
+            .header(
+                "x-vercel-signature",
+                stranger.sign_request_for_test_only(data),
+            )
+            .uri("/vercel")
+            .body(Body::from(&data[..]))?;
+        let response = app_service.call(request).await?;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(rx.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ingest_strict_unsigned_policy() -> Result<()> {
+        use crate::config::{RateLimitConfig, UnsignedPolicy};
+
+        let _ = tracing_subscriber::fmt().json().try_init();
+
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+
+        let limit = RateLimitConfig {
+            rate: 0.001,
+            burst: 2,
+        };
+        let state = types::AppState::new("test", b"deadbeef1234dacb4321", tx)?
+            .with_unsigned_policy(UnsignedPolicy::Strict, &limit);
+        let mut app = create_app(state);
+        let mut app_service = app.as_service();
+
+        let cases = [
+            (
+                include_str!("fixtures/sample_2.json"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            ("", StatusCode::OK),
+            (" [] ", StatusCode::OK),
+            // The two requests above used up the burst.
+            ("", StatusCode::TOO_MANY_REQUESTS),
+        ];
+        for (data, expected) in cases {
+            let request = Request::builder()
+                .method("POST")
+                .uri("/vercel")
+                .body(Body::from(data))?;
+            let response = app_service.call(request).await?;
+            assert_eq!(response.status(), expected, "payload: {data:?}");
+            assert_eq!(
+                response.headers().get("x-vercel-verify").is_some(),
+                expected == StatusCode::OK
+            );
+        }
+
+        assert_eq!(rx.len(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ingest_access_control() -> Result<()> {
+        use crate::config::Config;
+        use axum::extract::ConnectInfo;
+        use std::net::SocketAddr;
+
+        let _ = tracing_subscriber::fmt().json().try_init();
+        let data = include_bytes!("fixtures/sample_2.json");
+
+        let config = Config::from_toml(
+            r#"
+            [access]
+            allow = ["192.0.2.0/24"]
+            trusted_proxies = ["10.0.0.0/8"]
+            rate_limit = { rate = 0.001, burst = 2 }
+            "#,
+        )?;
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+        let state = types::AppState::new("test", b"deadbeef1234dacb4321", tx)?
+            .with_access(access::Access::new(&config.access));
+        let signature = state.sign_request_for_test_only(data);
+        let mut app = create_app(state);
+        let mut app_service = app.as_service();
+
+        let cases = [
+            ("192.0.2.1:1234", None, StatusCode::OK),
+            ("198.51.100.1:1234", None, StatusCode::FORBIDDEN),
+            // Only trusted proxies can vouch for a client.
+            (
+                "198.51.100.1:1234",
+                Some("192.0.2.1"),
+                StatusCode::FORBIDDEN,
+            ),
+            ("10.0.0.1:1234", Some("198.51.100.1"), StatusCode::FORBIDDEN),
+            ("10.0.0.1:1234", Some("192.0.2.1"), StatusCode::OK),
+            // That was 192.0.2.1's second request.
+            ("192.0.2.1:1234", None, StatusCode::TOO_MANY_REQUESTS),
+            ("192.0.2.2:1234", None, StatusCode::OK),
+        ];
+        for (peer, forwarded_for, expected) in cases {
+            let mut builder = Request::builder()
+                .method("POST")
+                .header("x-vercel-signature", &signature)
+                .uri("/vercel");
+            if let Some(forwarded_for) = forwarded_for {
+                builder = builder.header("x-forwarded-for", forwarded_for);
+            }
+            let mut request = builder.body(Body::from(&data[..]))?;
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(peer.parse::<SocketAddr>()?));
+            let response = app_service.call(request).await?;
+            assert_eq!(response.status(), expected, "{peer} {forwarded_for:?}");
+        }
+        assert_eq!(rx.len(), 9);
+
+        // Other routes aren't restricted.
+        let request = Request::builder().uri("/health").body(Body::empty())?;
+        let response = app_service.call(request).await?;
+        assert_eq!(response.status(), StatusCode::OK);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn ingest_invalid_signatures() -> Result<()> {
         let _ = tracing_subscriber::fmt().json().try_init();
@@ -203,6 +728,7 @@ mod tests {
             Some("aa"),
             Some("xx"),
             Some("000044d61091a62c339bdd0fb827afad8d61f556"),
+            Some("000044d61091a62c339bdd0fb827afad8d61f556000044d61091a62c339bdd0f"),
         ];
 
         for auth_header in test_auth_headers {
@@ -269,4 +795,53 @@ mod tests {
         assert_eq!(rx.len(), 0);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn ingest_captures_verified_payloads() -> Result<()> {
+        let _ = tracing_subscriber::fmt().json().try_init();
+        let dir = std::env::temp_dir().join(format!(
+            "vercel-log-drain-ingest-capture-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let capture = crate::capture::Capture::new(&dir, &Default::default(), &Default::default())?;
+
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel::<types::Message>();
+        let state = types::AppState::new("test", b"deadbeef1234dacb4321", tx)?
+            .with_capture(Some(std::sync::Arc::new(capture)));
+        let mut app = create_app(state.clone());
+        let mut app_service = app.as_service();
+
+        // Unparseable payloads are captured, but unverified ones aren't.
+        let data = "[{";
+        for signature in [
+            state.sign_request_for_test_only(data.as_bytes()),
+            "0".repeat(40),
+        ] {
+            let request = Request::builder()
+                .method("POST")
+                .header("x-vercel-signature", signature)
+                .uri("/vercel")
+                .body(Body::from(data))?;
+            app_service.call(request).await?;
+        }
+
+        let mut captured = Vec::new();
+        for _ in 0..50 {
+            captured = std::fs::read_dir(&dir)?.collect::<std::io::Result<Vec<_>>>()?;
+            if !captured.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(captured.len(), 1);
+        let captured: crate::capture::Captured =
+            serde_json::from_slice(&std::fs::read(captured[0].path())?)?;
+        assert_eq!(captured.body.as_deref(), Some(data));
+        assert_eq!(captured.secret, "default");
+        assert!(captured.headers.contains_key("x-vercel-signature"));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
 }
@@ -0,0 +1,270 @@
+//! Per-driver circuit breakers, so a driver that is down isn't sent (and
+//! doesn't fail) every single message.
+
+use crate::config::CircuitBreakerConfig;
+use crate::metrics;
+use crate::types::Message;
+use axum_prometheus::metrics::counter;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CircuitState {
+    /// Messages are sent.
+    #[default]
+    Closed,
+    /// Messages are buffered until the cooldown has passed.
+    Open,
+    /// One message is sent to probe whether the driver has recovered.
+    HalfOpen,
+}
+
+impl CircuitState {
+    fn as_str(self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+pub struct Breaker {
+    driver: String,
+    failure_threshold: u32,
+    cooldown: Duration,
+    buffer_size: usize,
+    max_attempts: u32,
+    state: CircuitState,
+    failures: u32,
+    opened_at: Option<Instant>,
+    buffer: VecDeque<Buffered>,
+}
+
+/// A message waiting to be sent, and how often sending it failed.
+pub struct Buffered {
+    pub message: Message,
+    attempts: u32,
+}
+
+impl Breaker {
+    pub fn new(driver: &str, config: &CircuitBreakerConfig) -> Self {
+        Self {
+            driver: driver.to_string(),
+            failure_threshold: config.failure_threshold,
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            buffer_size: config.buffer_size,
+            max_attempts: config.max_attempts,
+            state: CircuitState::Closed,
+            failures: 0,
+            opened_at: None,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Whether a message may be sent now, half-opening the circuit once the
+    /// cooldown has passed.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.state {
+            CircuitState::Closed | CircuitState::HalfOpen => true,
+            CircuitState::Open => {
+                let cooled = self
+                    .opened_at
+                    .is_some_and(|opened| now.saturating_duration_since(opened) >= self.cooldown);
+                if cooled {
+                    self.transition(CircuitState::HalfOpen);
+                }
+                cooled
+            }
+        }
+    }
+
+    /// Record the outcome of a send. Returns whether the circuit is (now) open.
+    pub fn record(&mut self, succeeded: bool, now: Instant) -> bool {
+        if succeeded {
+            self.failures = 0;
+            if self.state != CircuitState::Closed {
+                self.transition(CircuitState::Closed);
+            }
+            return false;
+        }
+        self.failures = self.failures.saturating_add(1);
+        let trips = match self.state {
+            CircuitState::Closed => self.failures >= self.failure_threshold,
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if trips {
+            self.opened_at = Some(now);
+            self.transition(CircuitState::Open);
+        }
+        self.state == CircuitState::Open
+    }
+
+    /// Keep a message for when the driver can be sent to again, dropping the
+    /// oldest if the buffer is full.
+    pub fn buffer(&mut self, message: Message) {
+        self.push(Buffered {
+            message,
+            attempts: 0,
+        });
+    }
+
+    /// Keep a message whose send just failed, to be retried.
+    pub fn buffer_failed(&mut self, message: Message) {
+        let mut buffered = Buffered {
+            message,
+            attempts: 0,
+        };
+        if self.retries(&mut buffered) {
+            self.push(buffered);
+        }
+    }
+
+    /// Count a failed send of a buffered message, returning whether it should
+    /// be tried again; if not it's dropped.
+    pub fn retries(&mut self, buffered: &mut Buffered) -> bool {
+        buffered.attempts += 1;
+        if buffered.attempts < self.max_attempts {
+            return true;
+        }
+        warn!(
+            driver = self.driver,
+            id = buffered.message.id,
+            attempts = buffered.attempts,
+            "giving up on message"
+        );
+        self.dropped();
+        false
+    }
+
+    fn push(&mut self, buffered: Buffered) {
+        if self.buffer_size == 0 {
+            self.dropped();
+            return;
+        }
+        if self.buffer.len() >= self.buffer_size {
+            self.buffer.pop_front();
+            self.dropped();
+        }
+        self.buffer.push_back(buffered);
+    }
+
+    /// Take the buffered messages, oldest first.
+    pub fn take_buffered(&mut self) -> VecDeque<Buffered> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Put back messages that couldn't be sent, ahead of anything buffered
+    /// since.
+    pub fn restore_buffered(&mut self, mut messages: VecDeque<Buffered>) {
+        messages.append(&mut self.buffer);
+        self.buffer = messages;
+        while self.buffer.len() > self.buffer_size {
+            self.buffer.pop_front();
+            self.dropped();
+        }
+    }
+
+    fn dropped(&self) {
+        counter!(metrics::name("circuit_dropped_messages"), "driver" => self.driver.clone())
+            .increment(1);
+    }
+
+    fn transition(&mut self, state: CircuitState) {
+        match state {
+            CircuitState::Open => warn!(
+                driver = self.driver,
+                failures = self.failures,
+                cooldown_secs = self.cooldown.as_secs(),
+                "circuit opened, buffering messages"
+            ),
+            CircuitState::HalfOpen => info!(driver = self.driver, "circuit half-open, probing"),
+            CircuitState::Closed => info!(
+                driver = self.driver,
+                buffered = self.buffer.len(),
+                "circuit closed"
+            ),
+        }
+        counter!(
+            metrics::name("circuit_transitions"),
+            "driver" => self.driver.clone(),
+            "state" => state.as_str()
+        )
+        .increment(1);
+        self.state = state;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_half_opens_and_closes() {
+        let mut breaker = Breaker::new(
+            "loki",
+            &CircuitBreakerConfig {
+                failure_threshold: 2,
+                cooldown_secs: 30,
+                buffer_size: 10,
+                max_attempts: 2,
+            },
+        );
+        let start = Instant::now();
+
+        assert!(breaker.allow(start));
+        assert!(!breaker.record(false, start));
+        assert!(breaker.record(false, start));
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow(start + Duration::from_secs(29)));
+
+        // A failed probe opens it again, for another cooldown.
+        let probe = start + Duration::from_secs(30);
+        assert!(breaker.allow(probe));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.record(false, probe));
+        assert!(!breaker.allow(probe + Duration::from_secs(29)));
+
+        assert!(breaker.allow(probe + Duration::from_secs(30)));
+        assert!(!breaker.record(true, probe + Duration::from_secs(30)));
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        // Failures are counted afresh.
+        assert!(!breaker.record(false, probe + Duration::from_secs(31)));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() -> anyhow::Result<()> {
+        let (payload, _) =
+            crate::types::VercelPayload::from_json(include_str!("fixtures/sample_2.json"))?;
+        let mut breaker = Breaker::new(
+            "loki",
+            &CircuitBreakerConfig {
+                max_attempts: 2,
+                ..Default::default()
+            },
+        );
+        breaker.buffer_failed(payload.0[0].clone());
+        breaker.buffer(payload.0[1].clone());
+        let mut buffered = breaker.take_buffered();
+        assert_eq!(buffered.len(), 2);
+
+        // The second failure of the first message is its last.
+        let mut first = buffered.pop_front().unwrap();
+        assert!(!breaker.retries(&mut first));
+        let mut second = buffered.pop_front().unwrap();
+        assert!(breaker.retries(&mut second));
+        Ok(())
+    }
+}
@@ -0,0 +1,409 @@
+//! Capture mode: write verified request bodies to disk, with their headers,
+//! to reproduce parse failures and build test fixtures from real traffic.
+
+use crate::config::{CaptureConfig, RedactConfig};
+use crate::health::now_millis;
+use crate::metrics;
+use crate::pipeline::{self, Redactor};
+use crate::types::{Message, PayloadFormat};
+use anyhow::{Context, Result};
+use axum::http::{header, HeaderMap};
+use axum_prometheus::metrics::counter;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// Headers whose values are never written.
+const REDACTED_HEADERS: &[&str] = &["authorization", "cookie", "proxy-authorization"];
+
+/// Captures waiting to be written; more are dropped, as each holds a whole
+/// request body.
+const QUEUE_SIZE: usize = 8;
+
+pub struct Capture {
+    sample_rate: f64,
+    redactor: Option<Redactor>,
+    writes: mpsc::Sender<Captured>,
+}
+
+/// One captured request, as written to disk.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Captured {
+    /// Milliseconds since the Unix epoch.
+    pub received_at: i64,
+    /// Name of the secret the request was signed with.
+    pub secret: String,
+    pub headers: BTreeMap<String, String>,
+    /// The decompressed body.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// The decompressed body, hex encoded, if it isn't UTF-8.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body_hex: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub redacted: bool,
+}
+
+/// Writes captures one at a time, so they are rotated in the order they
+/// were named.
+struct Writer {
+    dir: PathBuf,
+    max_files: usize,
+    /// Captures on disk, oldest first.
+    files: VecDeque<PathBuf>,
+    sequence: u64,
+}
+
+impl Capture {
+    /// Capture into `dir`, picking up the rotation where an earlier run left
+    /// it. Starts the task writing the files.
+    pub fn new(dir: &Path, config: &CaptureConfig, redact: &RedactConfig) -> Result<Self> {
+        let writer = Writer::new(dir, config.max_files)?;
+        let (writes, captures) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(writer.run(captures));
+        Self::with_writes(config, redact, writes)
+    }
+
+    fn with_writes(
+        config: &CaptureConfig,
+        redact: &RedactConfig,
+        writes: mpsc::Sender<Captured>,
+    ) -> Result<Self> {
+        Ok(Self {
+            sample_rate: config.sample_rate,
+            redactor: match config.redact {
+                true => Some(Redactor::new(redact)?),
+                false => None,
+            },
+            writes,
+        })
+    }
+
+    /// Capture a verified request, if it's sampled. The file is written in
+    /// the background, and the capture dropped if too many are waiting.
+    pub fn record(&self, headers: &HeaderMap, body: &[u8], secret: &str) {
+        if !self.sampled(body) {
+            return;
+        }
+        if self
+            .writes
+            .try_send(self.captured(headers, body, secret))
+            .is_err()
+        {
+            debug!("capture queue full, dropping capture");
+            counter!(metrics::name("capture_dropped")).increment(1);
+        }
+    }
+
+    /// Deterministically keep `sample_rate` of bodies, so a retried request
+    /// is captured every time or not at all.
+    fn sampled(&self, body: &[u8]) -> bool {
+        if self.sample_rate >= 1.0 {
+            return true;
+        }
+        pipeline::hash_fraction(body) < self.sample_rate
+    }
+
+    fn captured(&self, headers: &HeaderMap, body: &[u8], secret: &str) -> Captured {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok());
+        let headers = headers
+            .iter()
+            .map(|(name, value)| {
+                let value = match REDACTED_HEADERS.contains(&name.as_str()) {
+                    true => String::from("[REDACTED]"),
+                    false => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                };
+                (name.to_string(), value)
+            })
+            .collect();
+        let (body, body_hex) = match std::str::from_utf8(body) {
+            Ok(text) => match &self.redactor {
+                Some(redactor) => (Some(redact(redactor, content_type, text)), None),
+                None => (Some(text.to_string()), None),
+            },
+            Err(_) => (None, Some(hex::encode(body))),
+        };
+        Captured {
+            received_at: now_millis(),
+            secret: secret.to_string(),
+            headers,
+            body,
+            body_hex,
+            redacted: self.redactor.is_some(),
+        }
+    }
+}
+
+/// Redact a body the way the pipeline would redact its messages. Elements
+/// (or lines) that aren't messages only have the patterns scrubbed.
+fn redact(redactor: &Redactor, content_type: Option<&str>, body: &str) -> String {
+    match PayloadFormat::detect(content_type, body) {
+        PayloadFormat::Json => match serde_json::from_str::<Vec<&RawValue>>(body) {
+            Ok(elements) => {
+                let elements: Vec<_> = elements
+                    .into_iter()
+                    .map(|element| redact_element(redactor, element.get()))
+                    .collect();
+                format!("[{}]", elements.join(","))
+            }
+            Err(_) => scrub(redactor, body),
+        },
+        PayloadFormat::Ndjson => body
+            .lines()
+            .map(|line| match line.trim().is_empty() {
+                true => line.to_string(),
+                false => redact_element(redactor, line),
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+fn redact_element(redactor: &Redactor, element: &str) -> String {
+    let Ok(mut message) = serde_json::from_str::<Message>(element) else {
+        return scrub(redactor, element);
+    };
+    redactor.redact(&mut message);
+    // Fields the pipeline doesn't scrub, such as `path`, are scrubbed too.
+    match serde_json::to_string(&message) {
+        Ok(redacted) => scrub(redactor, &redacted),
+        Err(_) => scrub(redactor, element),
+    }
+}
+
+fn scrub(redactor: &Redactor, text: &str) -> String {
+    redactor.scrub(text).unwrap_or_else(|| text.to_string())
+}
+
+impl Writer {
+    fn new(dir: &Path, max_files: usize) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed creating capture directory {}", dir.display()))?;
+        // Only files named like captures, so nothing else in the directory
+        // is ever deleted.
+        let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| is_capture(path))
+            .collect();
+        // Named by receive time, so this is oldest first.
+        files.sort();
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_files,
+            files: files.into(),
+            sequence: 0,
+        })
+    }
+
+    async fn run(mut self, mut captures: mpsc::Receiver<Captured>) {
+        while let Some(captured) = captures.recv().await {
+            let written = tokio::task::spawn_blocking(move || {
+                let result = self.write(&captured);
+                (self, result)
+            })
+            .await;
+            let Ok((writer, result)) = written else {
+                warn!("capture writer panicked, no longer capturing");
+                return;
+            };
+            self = writer;
+            match result {
+                Ok(path) => {
+                    debug!(path = %path.display(), "captured payload");
+                    counter!(metrics::name("captured_payloads")).increment(1);
+                }
+                Err(e) => {
+                    warn!("failed capturing payload: {e:?}");
+                    counter!(metrics::name("capture_failures")).increment(1);
+                }
+            }
+        }
+    }
+
+    /// Write a capture, deleting the oldest ones past `max_files`.
+    fn write(&mut self, captured: &Captured) -> Result<PathBuf> {
+        let path = self.dir.join(format!(
+            "{}-{:06}.json",
+            captured.received_at, self.sequence
+        ));
+        self.sequence += 1;
+        std::fs::write(&path, serde_json::to_vec_pretty(captured)?)
+            .with_context(|| format!("failed writing {}", path.display()))?;
+
+        self.files.push_back(path.clone());
+        while self.files.len() > self.max_files {
+            let Some(oldest) = self.files.pop_front() else {
+                break;
+            };
+            if let Err(e) = std::fs::remove_file(&oldest) {
+                warn!(path = %oldest.display(), "failed removing old capture: {e}");
+            }
+        }
+        Ok(path)
+    }
+}
+
+/// Whether a file is named like a capture: `<millis>-<sequence>.json`.
+fn is_capture(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let Some((millis, sequence)) = name
+        .strip_suffix(".json")
+        .and_then(|stem| stem.split_once('-'))
+    else {
+        return false;
+    };
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    digits(millis) && digits(sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn capture(toml: &str) -> Result<(Capture, mpsc::Receiver<Captured>)> {
+        let config = Config::from_toml(toml)?;
+        let (writes, captures) = mpsc::channel(QUEUE_SIZE);
+        let capture =
+            Capture::with_writes(&config.ingest.capture, &config.pipeline.redact, writes)?;
+        Ok((capture, captures))
+    }
+
+    #[test]
+    fn rotates_and_redacts_captures() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("vercel-log-drain-capture-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+        let fixture = dir.join("sample_1.json");
+        std::fs::write(&fixture, "[]")?;
+        let (capture, _) = capture(
+            r#"
+            [ingest.capture]
+            redact = true
+
+            [pipeline.redact.scrub]
+            builtin = ["email"]
+            "#,
+        )?;
+        let mut writer = Writer::new(&dir, 2)?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "application/json".parse()?);
+        headers.insert("authorization", "Bearer secret".parse()?);
+        let mut written = Vec::new();
+        for body in [
+            &b"[]"[..],
+            b"[{\"message\":\"from jane@example.com\"}]",
+            &[0xff, 0xfe],
+        ] {
+            written.push(writer.write(&capture.captured(&headers, body, "default"))?);
+        }
+
+        // Only the newest two are kept.
+        assert!(!written[0].exists());
+        let captured: Captured = serde_json::from_slice(&std::fs::read(&written[1])?)?;
+        assert_eq!(
+            captured.body.as_deref(),
+            Some("[{\"message\":\"from [REDACTED:email]\"}]")
+        );
+        assert_eq!(captured.headers["authorization"], "[REDACTED]");
+        assert_eq!(captured.headers["content-type"], "application/json");
+        assert!(captured.redacted);
+        let captured: Captured = serde_json::from_slice(&std::fs::read(&written[2])?)?;
+        assert_eq!(captured.body_hex.as_deref(), Some("fffe"));
+
+        // A restart carries on rotating the captures already there, and
+        // leaves other files alone.
+        let mut writer = Writer::new(&dir, 2)?;
+        writer.write(&capture.captured(&headers, b"[]", "default"))?;
+        assert!(!written[1].exists());
+        assert!(fixture.exists());
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 3);
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn applies_field_rules_to_captures() -> Result<()> {
+        let (capture, _) = capture(
+            r#"
+            [ingest.capture]
+            redact = true
+
+            [pipeline.redact]
+            fields = [
+                { field = "proxy.clientIp", action = "truncate_ip" },
+                { field = "proxy.userAgent", action = "drop" },
+            ]
+            "#,
+        )?;
+        let (payload, _) =
+            crate::types::VercelPayload::from_json(include_str!("fixtures/sample_2.json"))?;
+        let message = serde_json::to_string(&payload.0[0])?;
+        let captured = |content_type: &str, body: String| -> Result<_> {
+            let mut headers = HeaderMap::new();
+            headers.insert("content-type", content_type.parse()?);
+            Ok(capture
+                .captured(&headers, body.as_bytes(), "default")
+                .body
+                .unwrap_or_default())
+        };
+
+        let body = captured("application/json", format!("[{message}]"))?;
+        let redacted: Vec<Message> = serde_json::from_str(&body)?;
+        let proxy = redacted[0].proxy.as_ref().unwrap();
+        assert_eq!(proxy.client_ip, "123.123.123.0");
+        assert!(proxy.user_agent.is_empty());
+
+        // Lines that aren't messages are kept as they were.
+        let body = captured("application/x-ndjson", format!("{message}\n{{\"id\":1}}"))?;
+        let (first, second) = body.split_once('\n').unwrap();
+        let redacted: Message = serde_json::from_str(first)?;
+        assert_eq!(redacted.proxy.unwrap().client_ip, "123.123.123.0");
+        assert_eq!(second, "{\"id\":1}");
+        Ok(())
+    }
+
+    #[test]
+    fn drops_captures_while_the_writer_is_behind() -> Result<()> {
+        let (capture, mut captures) = capture("")?;
+        for _ in 0..QUEUE_SIZE + 2 {
+            capture.record(&HeaderMap::new(), b"[]", "default");
+        }
+        let mut queued = 0;
+        while captures.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, QUEUE_SIZE);
+        Ok(())
+    }
+
+    #[test]
+    fn samples_bodies() -> Result<()> {
+        let (capture, _) = capture("[ingest.capture]\nsample_rate = 0.25")?;
+        let sampled = (0..1000)
+            .filter(|i| capture.sampled(i.to_string().as_bytes()))
+            .count();
+        assert!((150..350).contains(&sampled), "{sampled}");
+        assert_eq!(capture.sampled(b"[]"), capture.sampled(b"[]"));
+        Ok(())
+    }
+
+    #[test]
+    fn recognizes_capture_names() {
+        assert!(is_capture(Path::new("/tmp/1700000000000-000001.json")));
+        assert!(!is_capture(Path::new("/tmp/sample_1.json")));
+        assert!(!is_capture(Path::new("/tmp/1700000000000-000001.json.bak")));
+        assert!(!is_capture(Path::new("/tmp/test-build.json")));
+    }
+}
@@ -0,0 +1,1181 @@
+//! Declarative configuration.
+//!
+//! Settings are read from an optional TOML or YAML file and then overridden by
+//! any CLI flags or environment variables that were set. The merged [Config]
+//! is validated as a whole before anything is started.
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::http::HeaderValue;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::Level;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(deserialize_with = "deserialize_level")]
+    pub log: Option<Level>,
+    pub listen: ListenConfig,
+    pub auth: AuthConfig,
+    pub ingest: IngestConfig,
+    pub access: AccessConfig,
+    pub metrics: MetricsConfig,
+    pub tracing: TracingConfig,
+    pub admin: AdminConfig,
+    pub readiness: ReadinessConfig,
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// Log drivers, keyed by a name of your choosing.
+    pub drivers: BTreeMap<String, DriverConfig>,
+    pub routes: RoutesConfig,
+    pub pipeline: PipelineConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    pub ip: String,
+    pub port: u16,
+    /// Serve HTTPS instead of plain HTTP.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ListenConfig {
+    fn default() -> Self {
+        Self {
+            ip: String::from("0.0.0.0"),
+            port: 8000,
+            tls: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain.
+    pub cert: PathBuf,
+    /// PEM private key.
+    pub key: PathBuf,
+    /// Offer HTTP/2 (via ALPN) as well as HTTP/1.1.
+    #[serde(default)]
+    pub http2: bool,
+    /// How often to check the files for changes, in seconds; 0 disables it.
+    #[serde(default = "default_tls_watch_interval")]
+    pub watch_interval_secs: u64,
+}
+
+fn default_tls_watch_interval() -> u64 {
+    30
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub vercel_verify: Option<String>,
+    pub vercel_secret: Option<String>,
+    /// More secrets, tried in order after `vercel_secret`.
+    pub secrets: Vec<SecretConfig>,
+    pub signature_algorithm: SignatureAlgorithm,
+    /// How to answer requests without an `x-vercel-signature`.
+    pub unsigned: UnsignedConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnsignedConfig {
+    pub policy: UnsignedPolicy,
+    /// Per source IP, only used by the `strict` policy.
+    pub rate_limit: RateLimitConfig,
+}
+
+impl Default for UnsignedConfig {
+    fn default() -> Self {
+        Self {
+            policy: UnsignedPolicy::default(),
+            rate_limit: RateLimitConfig {
+                rate: 0.1,
+                burst: 5,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsignedPolicy {
+    /// Answer every unsigned request with the verification response.
+    #[default]
+    Allow,
+    /// Only answer unsigned requests with an empty (or `[]`) body, rate
+    /// limited per source IP.
+    Strict,
+}
+
+/// A token bucket: `burst` requests at once, refilled at `rate` per second.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+impl RateLimitConfig {
+    fn validate(&self, path: &str, errors: &mut Vec<String>) {
+        if !(self.rate.is_finite() && self.rate > 0.0) {
+            errors.push(format!("{path}.rate: must be greater than 0"));
+        }
+        if self.burst == 0 {
+            errors.push(format!("{path}.burst: must be at least 1"));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SecretConfig {
+    /// Reported in metrics; defaults to `secret_<index>`.
+    pub name: Option<String>,
+    pub secret: String,
+    /// Attached to every message signed with this secret, for routing.
+    pub tenant: Option<String>,
+    /// The `x-vercel-verify` token of the team this secret belongs to, if it
+    /// isn't `auth.vercel_verify`.
+    pub vercel_verify: Option<String>,
+}
+
+impl AuthConfig {
+    /// Every secret with its name, `vercel_secret` (named `default`) first.
+    pub fn all_secrets(&self) -> Vec<SecretConfig> {
+        let legacy = self.vercel_secret.iter().map(|secret| SecretConfig {
+            name: Some(String::from("default")),
+            secret: secret.clone(),
+            tenant: None,
+            vercel_verify: None,
+        });
+        let secrets = self
+            .secrets
+            .iter()
+            .enumerate()
+            .map(|(index, secret)| SecretConfig {
+                name: Some(
+                    secret
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| format!("secret_{index}")),
+                ),
+                ..secret.clone()
+            });
+        legacy.chain(secrets).collect()
+    }
+}
+
+/// The HMAC used for `x-vercel-signature`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureAlgorithm {
+    /// Accept either, telling them apart by the signature's prefix or length.
+    #[default]
+    Auto,
+    Sha1,
+    Sha256,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngestConfig {
+    /// Largest request body accepted after decompression, in bytes.
+    pub max_decompressed_bytes: usize,
+    /// Largest body decompressed before its signature is verified, to check
+    /// a signature made over the decompressed bytes; 0 only checks the bytes
+    /// as received.
+    pub max_unverified_decompressed_bytes: usize,
+    /// How many unparseable payload elements to keep for inspection.
+    pub quarantine_size: usize,
+    /// Log and count fields Vercel sends that the drain doesn't know about.
+    pub report_unknown_fields: bool,
+    pub capture: CaptureConfig,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            max_decompressed_bytes: crate::types::DEFAULT_MAX_DECOMPRESSED_BYTES,
+            max_unverified_decompressed_bytes:
+                crate::types::DEFAULT_MAX_UNVERIFIED_DECOMPRESSED_BYTES,
+            quarantine_size: crate::quarantine::DEFAULT_CAPACITY,
+            report_unknown_fields: false,
+            capture: CaptureConfig::default(),
+        }
+    }
+}
+
+/// Writing verified request bodies to disk, to reproduce parse failures and
+/// build test fixtures.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Directory captures are written to; capturing is off unless set.
+    pub dir: Option<PathBuf>,
+    /// Fraction of payloads captured, from 0 to 1.
+    pub sample_rate: f64,
+    /// Most captures kept; the oldest are deleted past this.
+    pub max_files: usize,
+    /// Apply the `pipeline.redact` field rules and scrub patterns to
+    /// captured bodies.
+    pub redact: bool,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            sample_rate: 1.0,
+            max_files: 1000,
+            redact: false,
+        }
+    }
+}
+
+/// Who may send requests to `/vercel`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// Networks allowed to connect; empty allows everyone.
+    pub allow: Vec<IpNet>,
+    /// Proxies whose `X-Forwarded-For` header names the real client.
+    pub trusted_proxies: Vec<IpNet>,
+    /// Per client IP.
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub prefix: String,
+    /// Most distinct values of each label taken from message data (project,
+    /// environment, ...); later values are counted as `_other`.
+    pub max_label_values: usize,
+    /// Count requests and their latency from the `proxy` field of request
+    /// logs, as `edge_*` metrics.
+    pub requests: bool,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            prefix: String::from("drain"),
+            max_label_values: 100,
+            requests: false,
+        }
+    }
+}
+
+/// Export of the drain's own traces.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    /// OTLP gRPC endpoint, e.g. `http://localhost:4317`; unset disables export.
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+    /// Fraction of ingest requests traced, from 0 to 1.
+    pub sample_ratio: f64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: String::from("vercel-log-drain"),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token for the `/admin` endpoints, which are disabled if unset.
+    pub token: Option<String>,
+    /// A separate address for the operational endpoints (`/health`,
+    /// `/metrics`, `/admin`), leaving only `/vercel` on the main listener.
+    pub listen: Option<SocketAddr>,
+}
+
+/// Applied to each driver separately.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Failed sends in a row that open the circuit.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before a message is sent as a probe.
+    pub cooldown_secs: u64,
+    /// Messages kept while the circuit is open; the oldest are dropped first.
+    pub buffer_size: usize,
+    /// Sends of one message before it is given up on, so a message the
+    /// driver rejects can't hold up the ones buffered behind it.
+    pub max_attempts: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown_secs: 30,
+            buffer_size: 1000,
+            max_attempts: 3,
+        }
+    }
+}
+
+/// When `/ready` starts answering `503`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadinessConfig {
+    /// Messages received but not yet sent to the drivers.
+    pub max_queue_depth: Option<usize>,
+    /// Failed sends in a row, for any one driver.
+    pub max_consecutive_failures: Option<u64>,
+}
+
+impl Default for ReadinessConfig {
+    fn default() -> Self {
+        Self {
+            max_queue_depth: Some(10_000),
+            max_consecutive_failures: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum DriverConfig {
+    Cloudwatch(CloudWatchConfig),
+    Loki(LokiConfig),
+}
+
+impl DriverConfig {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Cloudwatch(_) => "cloudwatch",
+            Self::Loki(_) => "loki",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CloudWatchConfig {
+    pub retention_in_days: i32,
+}
+
+impl Default for CloudWatchConfig {
+    fn default() -> Self {
+        Self {
+            retention_in_days: 90,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LokiConfig {
+    pub url: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutesConfig {
+    /// Drivers for messages that match no rule. Unset means every driver.
+    pub default: Option<Vec<String>>,
+    /// Checked in order; the first matching rule decides the drivers.
+    pub rules: Vec<RouteRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRule {
+    pub name: Option<String>,
+    #[serde(default, rename = "match")]
+    pub matches: MessageMatch,
+    pub drivers: Vec<String>,
+}
+
+/// Conditions on [crate::types::Message] fields. Every condition that is set
+/// must hold, and a condition holds if the field equals any of its values.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageMatch {
+    pub project_name: Option<Values>,
+    pub source: Option<Values>,
+    pub environment: Option<Values>,
+    pub branch: Option<Values>,
+    pub level: Option<Values>,
+    pub output_type: Option<Values>,
+    pub host: Option<Values>,
+    pub tenant: Option<Values>,
+}
+
+/// One string, or a list of them.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "OneOrMany")]
+pub struct Values(pub Vec<String>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<OneOrMany> for Values {
+    fn from(value: OneOrMany) -> Self {
+        match value {
+            OneOrMany::One(value) => Self(vec![value]),
+            OneOrMany::Many(values) => Self(values),
+        }
+    }
+}
+
+/// Stages every message passes through before it is routed.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// Checked in order; the first filter whose conditions hold decides.
+    pub filters: Vec<FilterConfig>,
+    /// Parse Lambda START/END/REPORT lines into the `lambda` field.
+    pub parse_lambda: bool,
+    pub enrich: EnrichConfig,
+    pub redact: RedactConfig,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            filters: Vec::new(),
+            parse_lambda: true,
+            enrich: EnrichConfig::default(),
+            redact: RedactConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterConfig {
+    pub name: Option<String>,
+    pub action: FilterAction,
+    /// Fraction of messages to keep, for `action = "sample"`.
+    pub rate: Option<f64>,
+    /// Field hashed to make sampling decisions, so related messages are kept
+    /// or dropped together.
+    #[serde(default = "default_sample_by")]
+    pub sample_by: String,
+    /// Every condition must hold; no conditions matches every message.
+    #[serde(default)]
+    pub when: Vec<Condition>,
+}
+
+fn default_sample_by() -> String {
+    String::from("requestId")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    Keep,
+    Drop,
+    Sample,
+}
+
+/// A predicate on a message field, addressed by its dotted JSON path, e.g.
+/// `source` or `proxy.userAgent`. List fields hold if any element does.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    pub field: String,
+    pub equals: Option<Values>,
+    pub starts_with: Option<Values>,
+    pub ends_with: Option<Values>,
+    pub contains: Option<Values>,
+    #[serde(default)]
+    pub ignore_case: bool,
+}
+
+impl Condition {
+    fn operator_count(&self) -> usize {
+        [
+            &self.equals,
+            &self.starts_with,
+            &self.ends_with,
+            &self.contains,
+        ]
+        .into_iter()
+        .filter(|op| op.is_some())
+        .count()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnrichConfig {
+    /// Every matching entry is applied in order, so later ones win.
+    #[serde(rename = "static")]
+    pub static_fields: Vec<StaticFields>,
+    pub derived: Vec<DerivedField>,
+    pub geoip: GeoIpConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticFields {
+    #[serde(default, rename = "match")]
+    pub matches: MessageMatch,
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DerivedField {
+    /// `latencyMs`: the log timestamp minus the proxy timestamp.
+    Latency,
+    /// `statusClass`: e.g. `2xx`, from the response status code.
+    StatusClass,
+}
+
+/// Local MaxMind-format (`.mmdb`) databases to look up `proxy.clientIp` in.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoIpConfig {
+    /// A GeoIP2/GeoLite2 Country or City database.
+    pub country_database: Option<PathBuf>,
+    /// A GeoIP2/GeoLite2 ASN database.
+    pub asn_database: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedactConfig {
+    /// Prepended to values before hashing; required by `action = "hash"`.
+    pub salt: Option<String>,
+    pub fields: Vec<FieldRedaction>,
+    /// Patterns scrubbed from `message`, including structured messages.
+    pub scrub: ScrubConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldRedaction {
+    pub field: RedactField,
+    pub action: RedactAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum RedactField {
+    #[serde(rename = "message")]
+    Message,
+    #[serde(rename = "path")]
+    Path,
+    #[serde(rename = "proxy.clientIp")]
+    ClientIp,
+    #[serde(rename = "proxy.userAgent")]
+    UserAgent,
+    #[serde(rename = "proxy.referer")]
+    Referer,
+    #[serde(rename = "proxy.path")]
+    ProxyPath,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactAction {
+    Drop,
+    Hash,
+    /// Keep the /24 of IPv4 or the /48 of IPv6 addresses.
+    TruncateIp,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScrubConfig {
+    pub builtin: Vec<BuiltinPattern>,
+    pub patterns: Vec<PatternConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BuiltinPattern {
+    Email,
+    /// Bearer tokens and JWTs.
+    Token,
+    /// Payment card numbers that pass a Luhn check.
+    Card,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PatternConfig {
+    pub name: String,
+    pub regex: String,
+}
+
+fn deserialize_level<'de, D>(deserializer: D) -> Result<Option<Level>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(level) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    Level::from_str(&level)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl Config {
+    /// Read a config file, picking the format from its extension.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        match extension {
+            Some("toml") => Self::from_toml(&contents),
+            Some("yaml" | "yml") => Self::from_yaml(&contents),
+            _ => Err(anyhow!("expected a .toml, .yaml or .yml file")),
+        }
+        .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    pub fn from_yaml(contents: &str) -> Result<Self> {
+        Ok(serde_yaml_ng::from_str(contents)?)
+    }
+
+    /// Check the merged configuration, reporting every problem at once.
+    pub fn validate(&self) -> Result<()> {
+        self.check(true)
+    }
+
+    /// Like [Config::validate], without requiring the Vercel secrets, which
+    /// `replay` doesn't use.
+    pub fn validate_for_replay(&self) -> Result<()> {
+        self.check(false)
+    }
+
+    fn check(&self, require_auth: bool) -> Result<()> {
+        let mut errors = Vec::new();
+
+        if require_auth && self.auth.vercel_verify.is_none() {
+            errors.push(String::from(
+                "auth.vercel_verify: must be set (or pass --vercel-verify / VERCEL_VERIFY)",
+            ));
+        }
+        match &self.auth.vercel_secret {
+            None if require_auth && self.auth.secrets.is_empty() => errors.push(String::from(
+                "auth.vercel_secret: must be set (or pass --vercel-secret / VERCEL_SECRET), or list auth.secrets",
+            )),
+            Some(secret) if secret.is_empty() => {
+                errors.push(String::from("auth.vercel_secret: must not be empty"))
+            }
+            _ => {}
+        }
+        self.auth
+            .unsigned
+            .rate_limit
+            .validate("auth.unsigned.rate_limit", &mut errors);
+        if let Some(rate_limit) = &self.access.rate_limit {
+            rate_limit.validate("access.rate_limit", &mut errors);
+        }
+        for (index, secret) in self.auth.secrets.iter().enumerate() {
+            if secret.secret.is_empty() {
+                errors.push(format!("auth.secrets[{index}]: secret must not be empty"));
+            }
+            if secret
+                .vercel_verify
+                .as_ref()
+                .is_some_and(|verify| HeaderValue::from_str(verify).is_err())
+            {
+                errors.push(format!(
+                    "auth.secrets[{index}]: vercel_verify must be a valid header value"
+                ));
+            }
+        }
+        let mut secret_names = BTreeSet::new();
+        for name in self
+            .auth
+            .all_secrets()
+            .into_iter()
+            .filter_map(|secret| secret.name)
+        {
+            if !secret_names.insert(name.clone()) {
+                errors.push(format!("auth.secrets: duplicate secret name `{name}`"));
+            }
+        }
+        if self.admin.token.as_ref().is_some_and(String::is_empty) {
+            errors.push(String::from("admin.token: must not be empty"));
+        }
+        if self.ingest.max_decompressed_bytes == 0 {
+            errors.push(String::from(
+                "ingest.max_decompressed_bytes: must be greater than 0",
+            ));
+        }
+        let capture = &self.ingest.capture;
+        if !(0.0..=1.0).contains(&capture.sample_rate) {
+            errors.push(String::from(
+                "ingest.capture.sample_rate: must be between 0 and 1",
+            ));
+        }
+        if capture.max_files == 0 {
+            errors.push(String::from(
+                "ingest.capture.max_files: must be greater than 0",
+            ));
+        }
+        let redact = &self.pipeline.redact;
+        if capture.redact
+            && redact.fields.is_empty()
+            && redact.scrub.builtin.is_empty()
+            && redact.scrub.patterns.is_empty()
+        {
+            errors.push(String::from(
+                "ingest.capture.redact: needs pipeline.redact fields or scrub patterns",
+            ));
+        }
+        if self.circuit_breaker.failure_threshold == 0 {
+            errors.push(String::from(
+                "circuit_breaker.failure_threshold: must be greater than 0",
+            ));
+        }
+        if self.circuit_breaker.max_attempts == 0 {
+            errors.push(String::from(
+                "circuit_breaker.max_attempts: must be greater than 0",
+            ));
+        }
+        if self.metrics.prefix.is_empty() {
+            errors.push(String::from("metrics.prefix: must not be empty"));
+        }
+        if self.metrics.max_label_values == 0 {
+            errors.push(String::from(
+                "metrics.max_label_values: must be greater than 0",
+            ));
+        }
+
+        for (name, driver) in &self.drivers {
+            if name.is_empty() {
+                errors.push(String::from("drivers: driver names must not be empty"));
+            }
+            if !driver_enabled(driver) {
+                errors.push(format!(
+                    "drivers.{name}: built without the `{}` feature",
+                    driver.kind()
+                ));
+            }
+            match driver {
+                DriverConfig::Cloudwatch(cloudwatch) => {
+                    if cloudwatch.retention_in_days <= 0 {
+                        errors.push(format!(
+                            "drivers.{name}.retention_in_days: must be greater than 0"
+                        ));
+                    }
+                }
+                DriverConfig::Loki(loki) => {
+                    if loki.url.is_empty() {
+                        errors.push(format!("drivers.{name}.url: must be set"));
+                    } else if !loki.url.starts_with("http://") && !loki.url.starts_with("https://")
+                    {
+                        errors.push(format!(
+                            "drivers.{name}.url: expected an http:// or https:// URL, got {:?}",
+                            loki.url
+                        ));
+                    }
+                }
+            }
+        }
+
+        let mut check_route_drivers = |field: String, drivers: &[String]| {
+            for driver in drivers {
+                if !self.drivers.contains_key(driver) {
+                    errors.push(format!("{field}: unknown driver {driver:?}"));
+                }
+            }
+        };
+        if let Some(default) = &self.routes.default {
+            check_route_drivers(String::from("routes.default"), default);
+        }
+        for (index, rule) in self.routes.rules.iter().enumerate() {
+            check_route_drivers(format!("routes.rules[{index}].drivers"), &rule.drivers);
+        }
+
+        for (index, filter) in self.pipeline.filters.iter().enumerate() {
+            let field = format!("pipeline.filters[{index}]");
+            match (filter.action, filter.rate) {
+                (FilterAction::Sample, None) => {
+                    errors.push(format!("{field}.rate: must be set to sample"))
+                }
+                (FilterAction::Sample, Some(rate)) if !(0.0..=1.0).contains(&rate) => {
+                    errors.push(format!("{field}.rate: must be between 0 and 1"))
+                }
+                (FilterAction::Keep | FilterAction::Drop, Some(_)) => {
+                    errors.push(format!("{field}.rate: only used to sample"))
+                }
+                _ => {}
+            }
+            for (cond_index, condition) in filter.when.iter().enumerate() {
+                if condition.operator_count() != 1 {
+                    errors.push(format!(
+                        "{field}.when[{cond_index}]: needs exactly one of equals, starts_with, \
+                         ends_with or contains"
+                    ));
+                }
+            }
+        }
+
+        if let Some(tls) = &self.listen.tls {
+            for (name, path) in [("cert", &tls.cert), ("key", &tls.key)] {
+                if !path.is_file() {
+                    errors.push(format!(
+                        "listen.tls.{name}: {} is not a file",
+                        path.display()
+                    ));
+                }
+            }
+        }
+
+        if self.tracing.otlp_endpoint.is_some() && !cfg!(feature = "otlp") {
+            errors.push(String::from(
+                "tracing.otlp_endpoint: built without the `otlp` feature",
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.tracing.sample_ratio) {
+            errors.push(String::from(
+                "tracing.sample_ratio: must be between 0 and 1",
+            ));
+        }
+
+        let geoip = &self.pipeline.enrich.geoip;
+        for (name, database) in [
+            ("country_database", &geoip.country_database),
+            ("asn_database", &geoip.asn_database),
+        ] {
+            let Some(database) = database else {
+                continue;
+            };
+            if !cfg!(feature = "geoip") {
+                errors.push(format!(
+                    "pipeline.enrich.geoip.{name}: built without the `geoip` feature"
+                ));
+            } else if !database.is_file() {
+                errors.push(format!(
+                    "pipeline.enrich.geoip.{name}: {} is not a file",
+                    database.display()
+                ));
+            }
+        }
+
+        let redact = &self.pipeline.redact;
+        for (index, redaction) in redact.fields.iter().enumerate() {
+            let field = format!("pipeline.redact.fields[{index}]");
+            if redaction.action == RedactAction::Hash && redact.salt.is_none() {
+                errors.push(format!(
+                    "{field}: hashing needs pipeline.redact.salt to be set"
+                ));
+            }
+            if redaction.action == RedactAction::TruncateIp
+                && redaction.field != RedactField::ClientIp
+            {
+                errors.push(format!(
+                    "{field}: truncate_ip only applies to proxy.clientIp"
+                ));
+            }
+        }
+        for (index, pattern) in redact.scrub.patterns.iter().enumerate() {
+            if let Err(e) = regex::Regex::new(&pattern.regex) {
+                errors.push(format!(
+                    "pipeline.redact.scrub.patterns[{index}].regex: {e}"
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        let mut message = String::from("invalid configuration:");
+        for error in errors {
+            let _ = write!(message, "\n  - {error}");
+        }
+        bail!(message)
+    }
+}
+
+fn driver_enabled(driver: &DriverConfig) -> bool {
+    match driver {
+        DriverConfig::Cloudwatch(_) => cfg!(feature = "cloudwatch"),
+        DriverConfig::Loki(_) => cfg!(feature = "loki"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        log = "debug"
+
+        [listen]
+        port = 9000
+
+        [auth]
+        vercel_verify = "verify"
+        vercel_secret = "secret"
+
+        [drivers.cw]
+        type = "cloudwatch"
+        retention_in_days = 30
+
+        [drivers.grafana]
+        type = "loki"
+        url = "http://localhost:3100/loki/api/v1/push"
+    "#;
+
+    const YAML: &str = r#"
+        log: debug
+        listen:
+          port: 9000
+        auth:
+          vercel_verify: verify
+          vercel_secret: secret
+        drivers:
+          cw:
+            type: cloudwatch
+            retention_in_days: 30
+          grafana:
+            type: loki
+            url: http://localhost:3100/loki/api/v1/push
+    "#;
+
+    #[test]
+    fn toml_and_yaml_are_equivalent() -> Result<()> {
+        for config in [Config::from_toml(TOML)?, Config::from_yaml(YAML)?] {
+            assert_eq!(config.log, Some(Level::DEBUG));
+            assert_eq!(config.listen.ip, "0.0.0.0");
+            assert_eq!(config.listen.port, 9000);
+            assert_eq!(config.metrics.prefix, "drain");
+            assert!(matches!(
+                config.drivers.get("cw"),
+                Some(DriverConfig::Cloudwatch(CloudWatchConfig {
+                    retention_in_days: 30
+                }))
+            ));
+            assert!(matches!(
+                config.drivers.get("grafana"),
+                Some(DriverConfig::Loki(_))
+            ));
+            config.validate()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn example_config_is_valid() -> Result<()> {
+        let config = Config::from_toml(include_str!("../config.example.toml"))?;
+        assert_eq!(config.drivers.len(), 2);
+        config.validate()
+    }
+
+    #[test]
+    fn parses_routes() -> Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [auth]
+            vercel_verify = "verify"
+            vercel_secret = "secret"
+
+            [drivers.grafana]
+            type = "loki"
+            url = "http://localhost:3100/loki/api/v1/push"
+
+            [routes]
+            default = []
+
+            [[routes.rules]]
+            name = "production lambdas"
+            match = { source = "lambda", environment = ["production", "preview"] }
+            drivers = ["grafana"]
+            "#,
+        )?;
+        config.validate()?;
+        let rule = &config.routes.rules[0];
+        assert_eq!(rule.matches.source.as_ref().unwrap().0, ["lambda"]);
+        assert_eq!(
+            rule.matches.environment.as_ref().unwrap().0,
+            ["production", "preview"]
+        );
+        assert!(config.routes.default.as_ref().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_routes_to_unknown_drivers() -> Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [auth]
+            vercel_verify = "verify"
+            vercel_secret = "secret"
+
+            [routes]
+            default = ["s3"]
+
+            [[routes.rules]]
+            drivers = ["loki"]
+            "#,
+        )?;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(
+            error.contains("routes.default: unknown driver \"s3\""),
+            "{error}"
+        );
+        assert!(error.contains("routes.rules[0].drivers"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn validates_filters() -> Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [auth]
+            vercel_verify = "verify"
+            vercel_secret = "secret"
+
+            [[pipeline.filters]]
+            action = "sample"
+
+            [[pipeline.filters]]
+            action = "drop"
+            rate = 0.5
+            when = [{ field = "source", equals = "static", contains = "stat" }]
+            "#,
+        )?;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(
+            error.contains("pipeline.filters[0].rate: must be set"),
+            "{error}"
+        );
+        assert!(
+            error.contains("pipeline.filters[1].rate: only used"),
+            "{error}"
+        );
+        assert!(error.contains("pipeline.filters[1].when[0]"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn validates_redactions() -> Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [auth]
+            vercel_verify = "verify"
+            vercel_secret = "secret"
+
+            [pipeline.redact]
+            fields = [
+                { field = "proxy.userAgent", action = "hash" },
+                { field = "proxy.referer", action = "truncate_ip" },
+            ]
+            scrub.patterns = [{ name = "broken", regex = "(" }]
+            "#,
+        )?;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("fields[0]: hashing needs"), "{error}");
+        assert!(error.contains("fields[1]: truncate_ip"), "{error}");
+        assert!(error.contains("scrub.patterns[0].regex"), "{error}");
+
+        let unknown_field = "[[pipeline.redact.fields]]\nfield = \"id\"\naction = \"drop\"";
+        assert!(Config::from_toml(unknown_field).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn validates_secrets() -> Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [auth]
+            vercel_verify = "verify"
+            secrets = [
+                { name = "team-a", secret = "a", tenant = "a" },
+                { secret = "b" },
+            ]
+            "#,
+        )?;
+        config.validate()?;
+        let names: Vec<_> = config
+            .auth
+            .all_secrets()
+            .into_iter()
+            .filter_map(|secret| secret.name)
+            .collect();
+        assert_eq!(names, ["team-a", "secret_1"]);
+
+        let config = Config::from_toml(
+            r#"
+            [auth]
+            vercel_verify = "verify"
+            vercel_secret = "legacy"
+            secrets = [
+              { name = "default", secret = "" },
+              { secret = "team-b", vercel_verify = "bad\nverify" },
+            ]
+            "#,
+        )?;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(
+            error.contains("auth.secrets[0]: secret must not be empty"),
+            "{error}"
+        );
+        assert!(
+            error.contains("auth.secrets[1]: vercel_verify must be a valid header value"),
+            "{error}"
+        );
+        assert!(error.contains("duplicate secret name `default`"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn replay_does_not_need_secrets() {
+        let config = Config::default();
+        assert!(config.validate().is_err());
+        assert!(config.validate_for_replay().is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        assert!(Config::from_toml("[listen]\nprot = 1").is_err());
+        assert!(Config::from_toml("[drivers.x]\ntype = \"loki\"\nurll = \"\"").is_err());
+        assert!(Config::from_toml("[drivers.x]\ntype = \"s3\"").is_err());
+    }
+
+    #[test]
+    fn validate_reports_every_problem() -> Result<()> {
+        let config = Config::from_toml(
+            "[ingest]\nmax_decompressed_bytes = 0\ncapture.redact = true\n[tracing]\nsample_ratio = 2.0\n[drivers.grafana]\ntype = \"loki\"\nurl = \"localhost\"",
+        )?;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("auth.vercel_verify"), "{error}");
+        assert!(error.contains("auth.vercel_secret"), "{error}");
+        assert!(error.contains("ingest.max_decompressed_bytes"), "{error}");
+        assert!(error.contains("ingest.capture.redact"), "{error}");
+        assert!(error.contains("tracing.sample_ratio"), "{error}");
+        assert!(error.contains("drivers.grafana.url"), "{error}");
+        Ok(())
+    }
+}
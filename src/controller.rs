@@ -1,14 +1,68 @@
+use crate::breaker::{Breaker, CircuitState};
+use crate::config::CircuitBreakerConfig;
+use crate::drivers::Drivers;
+use crate::health::Health;
+use crate::metrics;
+use crate::pipeline::Pipeline;
+use crate::routing::Routes;
 use crate::types::{LogDriver, Message};
 
-use anyhow::Result;
-use axum_prometheus::metrics::counter;
-use tokio::sync::mpsc;
-use tracing::{debug, error, info};
+use anyhow::{anyhow, bail, Result};
+use axum_prometheus::metrics::{counter, histogram};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+/// How often buffered messages are retried while no new messages arrive.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where messages go: the named drivers, and the routes choosing among them.
+#[derive(Default)]
+pub struct Outputs {
+    pub drivers: Drivers,
+    pub routes: Routes,
+    pub breaker: CircuitBreakerConfig,
+}
+
+/// Requests sent to a running [Controller].
+pub enum Command {
+    /// Replace the pipeline and outputs. The new drivers must already be
+    /// initialized.
+    Reconfigure {
+        pipeline: Box<Pipeline>,
+        outputs: Outputs,
+    },
+    /// Act on one driver, replying with the number of messages sent.
+    Driver {
+        driver: String,
+        action: DriverAction,
+        reply: oneshot::Sender<Result<usize>>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverAction {
+    /// Buffer the driver's messages instead of sending them.
+    Pause,
+    /// Send the driver's messages again, starting with the buffer.
+    Resume,
+    /// Send the buffered messages now, without waiting for the circuit
+    /// breaker's cooldown.
+    Flush,
+}
 
 pub struct Controller {
     _sender: mpsc::UnboundedSender<Message>,
     receiver: mpsc::UnboundedReceiver<Message>,
-    drivers: Vec<Box<dyn LogDriver>>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    pipeline: Pipeline,
+    outputs: Outputs,
+    health: Arc<Health>,
+    breakers: BTreeMap<String, Breaker>,
+    paused: BTreeSet<String>,
     processed_messages: usize,
 }
 
@@ -16,55 +70,450 @@ impl Controller {
     pub fn new(
         _sender: mpsc::UnboundedSender<Message>,
         receiver: mpsc::UnboundedReceiver<Message>,
-        drivers: Vec<Box<dyn LogDriver>>,
+        commands: mpsc::UnboundedReceiver<Command>,
+        pipeline: Pipeline,
+        outputs: Outputs,
     ) -> Self {
-        Self {
+        let mut controller = Self {
             _sender,
             receiver,
-            drivers,
+            commands,
+            pipeline,
+            outputs,
+            health: Arc::default(),
+            breakers: BTreeMap::new(),
+            paused: BTreeSet::new(),
             processed_messages: 0,
-        }
+        };
+        controller.reset_breakers();
+        controller
+    }
+
+    pub fn with_health(mut self, health: Arc<Health>) -> Self {
+        self.health = health;
+        self
     }
 
     pub async fn init(&mut self) -> Result<()> {
-        for driver in &mut self.drivers {
+        for driver in self.outputs.drivers.values_mut() {
             driver.init().await?;
         }
+        self.health.set_drivers(self.outputs.drivers.keys());
         info!("All drivers initialized");
         Ok(())
     }
 
     pub async fn run(&mut self) {
         info!("waiting for logs to send to drivers...");
-        while let Some(message) = self.receiver.recv().await {
-            let id = message.deployment_id.clone();
-            debug!(?id, "processing message...");
-            match self.handle_message(&message).await {
-                Ok(_) => {
-                    debug!(?id, "message handled successfully");
-                    self.processed_messages += 1;
-                    counter!("drain_processed_messages").increment(1);
+        let mut retry = tokio::time::interval(RETRY_INTERVAL);
+        retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            // Commands are only handled between messages, so a message is
+            // always sent with a single, complete driver set.
+            let message = tokio::select! {
+                biased;
+                Some(command) = self.commands.recv() => {
+                    self.handle_command(command).await;
+                    continue;
                 }
-                Err(e) => {
-                    error!(?id, "failed to handle message: {:?}", e);
-                    counter!("drain_failed_messages").increment(1);
+                message = self.receiver.recv() => match message {
+                    Some(message) => {
+                        self.health.dequeued();
+                        message
+                    }
+                    None => break,
+                },
+                _ = retry.tick() => {
+                    self.retry_buffered().await;
+                    continue;
                 }
+            };
+            let span = info_span!(
+                "process_message",
+                id = message.id,
+                project = message.project(),
+                source = message.source
+            );
+            message.trace.attach(&span);
+            self.process(message).instrument(span).await;
+        }
+    }
+
+    /// Run a message through the pipeline and send it to its drivers.
+    async fn process(&mut self, message: Message) {
+        let id = message.deployment_id.clone();
+        let Some(message) = self.pipeline.process(message) else {
+            debug!(?id, "message dropped by pipeline");
+            return;
+        };
+        debug!(?id, "processing message...");
+        match self.handle_message(&message).await {
+            Ok(_) => {
+                debug!(?id, "message handled successfully");
+                self.processed_messages += 1;
+                counter!(
+                    metrics::name("processed_messages"),
+                    "project" => metrics::label("project", Some(message.project()))
+                )
+                .increment(1);
             }
-            if self.processed_messages.is_multiple_of(100) {
+            Err(e) => {
+                error!(?id, "failed to handle message: {:?}", e);
+                counter!(
+                    metrics::name("failed_messages"),
+                    "project" => metrics::label("project", Some(message.project()))
+                )
+                .increment(1);
+            }
+        }
+        if self.processed_messages.is_multiple_of(100) {
+            info!(
+                processed_messages = self.processed_messages,
+                "processed 100 messages..."
+            );
+        }
+    }
+
+    async fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Reconfigure { pipeline, outputs } => {
                 info!(
-                    processed_messages = self.processed_messages,
-                    "processed 100 messages..."
+                    old_drivers = ?self.outputs.drivers.keys().collect::<Vec<_>>(),
+                    new_drivers = ?outputs.drivers.keys().collect::<Vec<_>>(),
+                    "replacing pipeline and outputs"
                 );
+                self.pipeline = *pipeline;
+                self.outputs = outputs;
+                self.health.set_drivers(self.outputs.drivers.keys());
+                self.paused
+                    .retain(|name| self.outputs.drivers.contains_key(name));
+                self.reset_breakers();
+            }
+            Command::Driver {
+                driver,
+                action,
+                reply,
+            } => {
+                let result = self.handle_driver_action(&driver, action).await;
+                match &result {
+                    Ok(sent) => info!(driver, ?action, sent, "driver action applied"),
+                    Err(e) => warn!(driver, ?action, "driver action failed: {e:#}"),
+                }
+                // The admin request may have given up waiting.
+                let _ = reply.send(result);
             }
         }
     }
 
+    async fn handle_driver_action(&mut self, name: &str, action: DriverAction) -> Result<usize> {
+        let (Some(driver), Some(breaker)) = (
+            self.outputs.drivers.get_mut(name),
+            self.breakers.get_mut(name),
+        ) else {
+            bail!("no driver named `{name}`");
+        };
+        let sent = match action {
+            DriverAction::Pause => {
+                self.paused.insert(name.to_string());
+                0
+            }
+            DriverAction::Resume => {
+                self.paused.remove(name);
+                flush(name, driver, breaker, &self.health, Instant::now()).await
+            }
+            DriverAction::Flush if self.paused.contains(name) => {
+                bail!("driver `{name}` is paused")
+            }
+            DriverAction::Flush => flush(name, driver, breaker, &self.health, Instant::now()).await,
+        };
+        let paused = self.paused.contains(name);
+        sync_health(&self.health, name, breaker, paused);
+        Ok(sent)
+    }
+
+    /// Start every driver with a closed circuit, keeping the messages
+    /// buffered for drivers that remain.
+    fn reset_breakers(&mut self) {
+        let mut old = std::mem::take(&mut self.breakers);
+        for name in self.outputs.drivers.keys() {
+            let mut breaker = Breaker::new(name, &self.outputs.breaker);
+            if let Some(mut previous) = old.remove(name) {
+                breaker.restore_buffered(previous.take_buffered());
+            }
+            sync_health(&self.health, name, &breaker, self.paused.contains(name));
+            self.breakers.insert(name.clone(), breaker);
+        }
+    }
+
     async fn handle_message(&mut self, message: &Message) -> Result<()> {
-        for driver in &mut self.drivers {
-            if let Err(e) = driver.send_log(message).await {
-                error!("Failed to send log to driver: {:?}", e);
+        let destinations = self.outputs.routes.destinations(message);
+        let now = Instant::now();
+        for (name, driver) in &mut self.outputs.drivers {
+            if !destinations.includes(name) {
+                continue;
+            }
+            let breaker = self
+                .breakers
+                .get_mut(name)
+                .ok_or_else(|| anyhow!("no circuit breaker for driver `{name}`"))?;
+            let paused = self.paused.contains(name);
+            if paused || !breaker.allow(now) {
+                breaker.buffer(message.clone());
+            } else {
+                // Whatever was buffered goes first, to keep messages in
+                // order; if it can't all be sent, this one waits behind it.
+                flush(name, driver, breaker, &self.health, now).await;
+                if breaker.buffered() > 0 {
+                    breaker.buffer(message.clone());
+                } else if !deliver(name, driver, breaker, &self.health, message, now).await {
+                    breaker.buffer_failed(message.clone());
+                }
+            }
+            sync_health(&self.health, name, breaker, paused);
+        }
+        Ok(())
+    }
+
+    /// Send what drivers buffered, once they may be sent to again, so the
+    /// buffers drain without waiting for new messages.
+    async fn retry_buffered(&mut self) {
+        let now = Instant::now();
+        for (name, driver) in &mut self.outputs.drivers {
+            let Some(breaker) = self.breakers.get_mut(name) else {
+                continue;
+            };
+            let paused = self.paused.contains(name);
+            if breaker.buffered() == 0 || paused || !breaker.allow(now) {
+                continue;
             }
+            let sent = flush(name, driver, breaker, &self.health, now).await;
+            debug!(driver = name, sent, "retried buffered messages");
+            sync_health(&self.health, name, breaker, paused);
         }
+    }
+}
+
+/// Send a message to one driver and record the outcome. Returns whether it
+/// was sent.
+async fn deliver(
+    name: &str,
+    driver: &mut Box<dyn LogDriver>,
+    breaker: &mut Breaker,
+    health: &Health,
+    message: &Message,
+    now: Instant,
+) -> bool {
+    let started = Instant::now();
+    let result = driver
+        .send_log(message)
+        .instrument(info_span!("driver_send", driver = name))
+        .await;
+    histogram!(metrics::name("driver_send_seconds"), "driver" => name.to_string())
+        .record(started.elapsed().as_secs_f64());
+    let outcome = match result {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    };
+    counter!(
+        metrics::name("driver_sent_messages"),
+        "driver" => name.to_string(),
+        "result" => outcome
+    )
+    .increment(1);
+    health.record(name, &result);
+    if let Err(e) = &result {
+        error!(driver = name, "Failed to send log to driver: {:?}", e);
+    }
+    breaker.record(result.is_ok(), now);
+    result.is_ok()
+}
+
+/// Send the buffered messages, oldest first, stopping at the first one that
+/// fails so they stay in order. Returns how many were sent.
+async fn flush(
+    name: &str,
+    driver: &mut Box<dyn LogDriver>,
+    breaker: &mut Breaker,
+    health: &Health,
+    now: Instant,
+) -> usize {
+    let mut buffered = breaker.take_buffered();
+    let mut sent = 0;
+    while let Some(mut next) = buffered.pop_front() {
+        if deliver(name, driver, breaker, health, &next.message, now).await {
+            sent += 1;
+            continue;
+        }
+        let retries = breaker.retries(&mut next);
+        if retries {
+            buffered.push_front(next);
+        }
+        if retries || breaker.state() == CircuitState::Open {
+            break;
+        }
+    }
+    breaker.restore_buffered(buffered);
+    sent
+}
+
+fn sync_health(health: &Health, name: &str, breaker: &Breaker, paused: bool) {
+    health.update(name, |driver| {
+        driver.circuit = breaker.state();
+        driver.buffered = breaker.buffered();
+        driver.paused = paused;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PipelineConfig;
+    use crate::types::VercelPayload;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    /// Fails while `down` is set, and remembers the messages it was sent.
+    #[derive(Clone, Default)]
+    struct FlakyDriver {
+        down: Arc<AtomicBool>,
+        attempts: Arc<Mutex<usize>>,
+        sent: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LogDriver for FlakyDriver {
+        async fn init(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn send_log(&mut self, message: &Message) -> Result<()> {
+            *self.attempts.lock().unwrap() += 1;
+            if self.down.load(Ordering::Relaxed) {
+                anyhow::bail!("driver is down");
+            }
+            self.sent.lock().unwrap().push(message.id.clone());
+            Ok(())
+        }
+    }
+
+    fn controller(driver: &FlakyDriver, health: Arc<Health>) -> Result<Controller> {
+        let mut drivers = Drivers::new();
+        drivers.insert(String::from("loki"), Box::new(driver.clone()));
+        let outputs = Outputs {
+            drivers,
+            routes: Routes::default(),
+            breaker: CircuitBreakerConfig {
+                failure_threshold: 2,
+                cooldown_secs: 1,
+                buffer_size: 10,
+                max_attempts: 3,
+            },
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (_commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let pipeline = Pipeline::new(&PipelineConfig::default())?;
+        Ok(Controller::new(tx, rx, commands_rx, pipeline, outputs).with_health(health))
+    }
+
+    fn message(id: &str) -> Result<Message> {
+        let (messages, _) = VercelPayload::from_json(include_str!("fixtures/sample_1.json"))?;
+        Ok(Message {
+            id: id.to_string(),
+            ..messages.0[0].clone()
+        })
+    }
+
+    #[tokio::test]
+    async fn buffers_while_circuit_is_open() -> Result<()> {
+        let driver = FlakyDriver::default();
+        let health = Arc::new(Health::default());
+        let mut controller = controller(&driver, health.clone())?;
+        controller.init().await?;
+
+        driver.down.store(true, Ordering::Relaxed);
+        for id in ["a", "b", "c", "d"] {
+            controller.handle_message(&message(id)?).await?;
+        }
+        // "a" fails, and fails again when retried ahead of "b", which opens
+        // the circuit; everything is kept.
+        assert_eq!(*driver.attempts.lock().unwrap(), 2);
+        let loki = &health.drivers()["loki"];
+        assert_eq!(loki.circuit, CircuitState::Open);
+        assert_eq!(loki.buffered, 4);
+
+        // Back up: after the cooldown the buffer is sent, in order, without
+        // waiting for another message.
+        driver.down.store(false, Ordering::Relaxed);
+        controller.retry_buffered().await;
+        assert!(driver.sent.lock().unwrap().is_empty());
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        controller.retry_buffered().await;
+        controller.handle_message(&message("e")?).await?;
+        assert_eq!(*driver.sent.lock().unwrap(), ["a", "b", "c", "d", "e"]);
+        let loki = &health.drivers()["loki"];
+        assert_eq!(loki.circuit, CircuitState::Closed);
+        assert_eq!(loki.buffered, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_message_after_max_attempts() -> Result<()> {
+        let driver = FlakyDriver::default();
+        let health = Arc::new(Health::default());
+        let mut controller = controller(&driver, health.clone())?;
+        controller.init().await?;
+
+        // Fails once, then on the first two retries: each time `b` has to
+        // wait behind it.
+        driver.down.store(true, Ordering::Relaxed);
+        controller.handle_message(&message("a")?).await?;
+        controller.handle_message(&message("b")?).await?;
+        assert_eq!(health.drivers()["loki"].buffered, 2);
+
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        controller.retry_buffered().await;
+        // The third failure was `a`'s last.
+        assert_eq!(health.drivers()["loki"].buffered, 1);
+
+        driver.down.store(false, Ordering::Relaxed);
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        controller.retry_buffered().await;
+        assert_eq!(*driver.sent.lock().unwrap(), ["b"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pauses_and_resumes_drivers() -> Result<()> {
+        let driver = FlakyDriver::default();
+        let health = Arc::new(Health::default());
+        let mut controller = controller(&driver, health.clone())?;
+        controller.init().await?;
+
+        controller
+            .handle_driver_action("loki", DriverAction::Pause)
+            .await?;
+        for id in ["a", "b"] {
+            controller.handle_message(&message(id)?).await?;
+        }
+        assert!(driver.sent.lock().unwrap().is_empty());
+        assert!(health.drivers()["loki"].paused);
+        assert!(controller
+            .handle_driver_action("loki", DriverAction::Flush)
+            .await
+            .is_err());
+
+        let sent = controller
+            .handle_driver_action("loki", DriverAction::Resume)
+            .await?;
+        assert_eq!(sent, 2);
+        assert_eq!(*driver.sent.lock().unwrap(), ["a", "b"]);
+        assert!(!health.drivers()["loki"].paused);
+
+        assert!(controller
+            .handle_driver_action("cloudwatch", DriverAction::Pause)
+            .await
+            .is_err());
         Ok(())
     }
 }
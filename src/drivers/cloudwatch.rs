@@ -1,3 +1,4 @@
+use crate::metrics;
 use crate::types::{LogDriver, Message};
 use anyhow::Result;
 use async_trait::async_trait;
@@ -9,22 +10,28 @@ use aws_sdk_cloudwatchlogs::{
     },
     types::InputLogEvent,
 };
+use axum_prometheus::metrics::counter;
 use core::result::Result::Ok;
 use std::collections::HashSet;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 pub struct CloudWatchDriver {
+    /// As configured, for metrics.
+    name: String,
     client: aws_sdk_cloudwatchlogs::Client,
     groups: HashSet<String>,
     streams: HashSet<String>,
+    retention_in_days: i32,
 }
 
 impl CloudWatchDriver {
-    pub fn new(client: aws_sdk_cloudwatchlogs::Client) -> Self {
+    pub fn new(name: &str, client: aws_sdk_cloudwatchlogs::Client, retention_in_days: i32) -> Self {
         Self {
+            name: name.to_string(),
             client,
             groups: HashSet::new(),
             streams: HashSet::new(),
+            retention_in_days,
         }
     }
     async fn create_group(&mut self, group_name: &str) -> Result<()> {
@@ -53,7 +60,7 @@ impl CloudWatchDriver {
             .client
             .put_retention_policy()
             .log_group_name(group_name)
-            .retention_in_days(90)
+            .retention_in_days(self.retention_in_days)
             .send()
             .await
             .map_err(SdkError::into_service_error)
@@ -190,6 +197,7 @@ impl LogDriver for CloudWatchDriver {
         self.check_or_create(&group_name, &stream_name).await?;
 
         let payload = serde_json::to_string(&message)?;
+        let bytes = payload.len() as u64;
         let log_event = InputLogEvent::builder()
             .timestamp(message.timestamp)
             .message(payload)
@@ -202,9 +210,11 @@ impl LogDriver for CloudWatchDriver {
             .log_stream_name(&stream_name)
             .log_events(log_event.clone())
             .send()
+            .instrument(info_span!("put_log_events", attempt = retries + 1))
             .await
         {
             retries += 1;
+            counter!(metrics::name("driver_retries"), "driver" => self.name.clone()).increment(1);
             if retries > 5 {
                 warn!(
                     ?group_name,
@@ -232,6 +242,8 @@ impl LogDriver for CloudWatchDriver {
             }
             info!(id = message.id, ?retries, "retrying message...");
         }
+        counter!(metrics::name("driver_sent_bytes"), "driver" => self.name.clone())
+            .increment(bytes);
         return Ok(());
     }
 }
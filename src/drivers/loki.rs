@@ -1,11 +1,15 @@
+use crate::metrics;
 use crate::types::{LogDriver, Message};
 use anyhow::Result;
 use async_trait::async_trait;
+use axum_prometheus::metrics::counter;
 use reqwest::Client as HttpClient;
 use serde_json::json;
 use tracing::debug;
 
 pub struct LokiDriver {
+    /// As configured, for metrics.
+    name: String,
     client: HttpClient,
     url: String,
     username: String,
@@ -13,8 +17,9 @@ pub struct LokiDriver {
 }
 
 impl LokiDriver {
-    pub fn new(url: String, username: String, password: String) -> Self {
+    pub fn new(name: &str, url: String, username: String, password: String) -> Self {
         Self {
+            name: name.to_string(),
             client: HttpClient::new(),
             url,
             username,
@@ -51,11 +56,13 @@ impl LogDriver for LokiDriver {
         });
         debug!("formed payload");
 
+        let body = serde_json::to_vec(&payload)?;
+        let bytes = body.len() as u64;
         let mut req = self
             .client
             .post(&self.url)
             .header("Content-Type", "application/json")
-            .json(&payload);
+            .body(body);
 
         debug!("built request");
         if !self.username.is_empty() && !self.password.is_empty() {
@@ -67,6 +74,8 @@ impl LogDriver for LokiDriver {
         if !response.status().is_success() {
             anyhow::bail!("Failed to send log: {}", response.status());
         }
+        counter!(metrics::name("driver_sent_bytes"), "driver" => self.name.clone())
+            .increment(bytes);
 
         Ok(())
     }
@@ -7,3 +7,62 @@ mod loki;
 pub use cloudwatch::CloudWatchDriver;
 #[cfg(feature = "loki")]
 pub use loki::LokiDriver;
+
+use crate::config::DriverConfig;
+use crate::types::LogDriver;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use tracing::debug;
+
+/// Drivers keyed by their configured name.
+pub type Drivers = BTreeMap<String, Box<dyn LogDriver>>;
+
+/// Build every configured driver. Drivers still need to be initialized.
+pub async fn from_config(configs: &BTreeMap<String, DriverConfig>) -> Result<Drivers> {
+    let mut drivers = Drivers::new();
+
+    #[cfg(feature = "cloudwatch")]
+    let mut aws_config = None;
+
+    for (name, config) in configs {
+        match config {
+            #[cfg(feature = "cloudwatch")]
+            DriverConfig::Cloudwatch(config) => {
+                if aws_config.is_none() {
+                    aws_config = Some(
+                        aws_config::load_defaults(aws_config::BehaviorVersion::v2024_03_28()).await,
+                    );
+                }
+                let cwl_client = aws_sdk_cloudwatchlogs::Client::new(aws_config.as_ref().unwrap());
+                drivers.insert(
+                    name.clone(),
+                    Box::new(CloudWatchDriver::new(
+                        name,
+                        cwl_client,
+                        config.retention_in_days,
+                    )),
+                );
+            }
+            #[cfg(feature = "loki")]
+            DriverConfig::Loki(config) => {
+                drivers.insert(
+                    name.clone(),
+                    Box::new(LokiDriver::new(
+                        name,
+                        config.url.clone(),
+                        config.username.clone(),
+                        config.password.clone(),
+                    )),
+                );
+            }
+            #[allow(unreachable_patterns)]
+            _ => bail!(
+                "driver {name:?} needs the `{}` feature, which is not enabled",
+                config.kind()
+            ),
+        }
+        debug!(?name, kind = config.kind(), "added driver");
+    }
+
+    Ok(drivers)
+}
@@ -0,0 +1,74 @@
+//! Request body `Content-Encoding` support.
+
+use std::io::Read;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Zstd,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The decompressed body is larger than the configured limit.
+    TooLarge,
+    Invalid(std::io::Error),
+}
+
+impl ContentEncoding {
+    /// Parse a `Content-Encoding` header value; `None` if it's unsupported.
+    pub fn from_header(value: Option<&str>) -> Option<Self> {
+        let Some(value) = value else {
+            return Some(Self::Identity);
+        };
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Some(Self::Identity),
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Identity => "identity",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// Decompress `body`, refusing to produce more than `limit` bytes.
+    pub fn decode(self, body: &[u8], limit: usize) -> Result<Vec<u8>, DecodeError> {
+        let reader: Box<dyn Read + '_> = match self {
+            Self::Identity => Box::new(body),
+            Self::Gzip => Box::new(flate2::read::MultiGzDecoder::new(body)),
+            // HTTP's "deflate" is zlib-wrapped; some clients send raw deflate.
+            Self::Deflate if is_zlib(body) => Box::new(flate2::read::ZlibDecoder::new(body)),
+            Self::Deflate => Box::new(flate2::read::DeflateDecoder::new(body)),
+            Self::Zstd => Box::new(zstd::Decoder::new(body).map_err(DecodeError::Invalid)?),
+        };
+
+        let mut decoded = Vec::new();
+        // Read one byte past the limit, to tell "exactly at" from "over" it.
+        reader
+            .take(limit as u64 + 1)
+            .read_to_end(&mut decoded)
+            .map_err(DecodeError::Invalid)?;
+        if decoded.len() > limit {
+            return Err(DecodeError::TooLarge);
+        }
+        Ok(decoded)
+    }
+}
+
+/// Whether the body starts with a valid zlib header (RFC 1950).
+fn is_zlib(body: &[u8]) -> bool {
+    match body {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && u16::from_be_bytes([*cmf, *flg]) % 31 == 0,
+        _ => false,
+    }
+}
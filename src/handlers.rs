@@ -1,13 +1,27 @@
+use crate::access::ClientIp;
+use crate::encoding::{ContentEncoding, DecodeError};
+use crate::health::Health;
+use crate::metrics;
+use crate::quarantine;
+use crate::telemetry::TraceContext;
 use crate::types;
 use axum::{
     body::Bytes,
     extract::State,
-    http::{header::HeaderMap, StatusCode},
+    http::{
+        header::{self, HeaderMap},
+        StatusCode,
+    },
     response::IntoResponse,
+    Extension, Json,
 };
-use axum_prometheus::metrics::counter;
+use axum_prometheus::metrics::{counter, histogram};
 use core::str;
-use tracing::{debug, error, warn};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tracing::field::Empty;
+use tracing::{debug, error, info, info_span, instrument, warn, Instrument, Span};
 
 pub async fn root() -> impl IntoResponse {
     StatusCode::OK
@@ -17,8 +31,23 @@ pub async fn health_check() -> impl IntoResponse {
     StatusCode::OK
 }
 
+/// Per-driver status, answering `503` once a readiness threshold is crossed.
+pub async fn ready(State(health): State<Arc<Health>>) -> impl IntoResponse {
+    let readiness = health.readiness();
+    let status = match readiness.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(readiness))
+}
+
+#[instrument(
+    skip_all,
+    fields(bytes = body.len(), encoding = Empty, format = Empty, messages = Empty)
+)]
 pub async fn ingest(
     State(state): State<types::AppState>,
+    client_ip: Option<Extension<ClientIp>>,
     headers: HeaderMap,
     body: Bytes,
 ) -> impl IntoResponse {
@@ -26,59 +55,267 @@ pub async fn ingest(
 
     let Some(sig_header) = headers.get("x-vercel-signature") else {
         warn!(?headers, "received payload without signature");
-        counter!("drain_recv_missing_signature").increment(1);
+        counter!(metrics::name("recv_missing_signature")).increment(1);
+        if let Some(limiter) = &state.unsigned_limiter {
+            // Vercel's verification request has no body, so anything else is
+            // someone probing the endpoint.
+            if !(body.is_empty() || body.trim_ascii() == b"[]") {
+                counter!(metrics::name("recv_unsigned_rejected"), "reason" => "body").increment(1);
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+            let ip = client_ip.map_or(IpAddr::from([0, 0, 0, 0]), |Extension(ClientIp(ip))| ip);
+            if !limiter.check(ip) {
+                warn!(%ip, "rate limited unsigned request");
+                counter!(metrics::name("recv_unsigned_rejected"), "reason" => "rate_limited")
+                    .increment(1);
+                return StatusCode::TOO_MANY_REQUESTS.into_response();
+            }
+        }
         return state.ok_response();
     };
 
-    // Catch whenever we get a signature header which is not 20 bytes encoded
-    // in base16.
-    let Some(sig_bytes) = sig_header.to_str().ok().and_then(|sig| {
-        let mut sig_bytes = [0; 20];
-        hex::decode_to_slice(sig, &mut sig_bytes)
-            .ok()
-            .map(|()| sig_bytes)
-    }) else {
+    // Catch whenever we get a signature header which is not a SHA1 or SHA256
+    // digest (of the configured algorithm) encoded in base16.
+    let Some(signature) = sig_header
+        .to_str()
+        .ok()
+        .and_then(|sig| state.parse_signature(sig))
+    else {
         warn!(?headers, "received payload with invalid signature");
-        counter!("drain_recv_invalid_signature").increment(1);
+        counter!(metrics::name("recv_invalid_signature")).increment(1);
         return StatusCode::UNAUTHORIZED.into_response();
     };
 
-    // Catch whenever the signature is invalid.
-    if state.verify_signature(&body, &sig_bytes).is_err() {
-        error!(?headers, "failed verifying signature");
-        counter!("drain_failed_verify_signature").increment(1);
-        return StatusCode::UNAUTHORIZED.into_response();
+    let content_encoding = headers
+        .get(header::CONTENT_ENCODING)
+        .map(|encoding| encoding.to_str().unwrap_or_default());
+    let Some(encoding) = ContentEncoding::from_header(content_encoding) else {
+        warn!(
+            ?content_encoding,
+            "received payload with unsupported encoding"
+        );
+        counter!(metrics::name("recv_unsupported_encoding")).increment(1);
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    };
+
+    Span::current().record("encoding", encoding.as_str());
+
+    let verified = async {
+        if let Ok(secret) = state.verify_signature(&body, &signature) {
+            return Ok((secret, None));
+        }
+        // Vercel signs the bytes it sends, but a proxy in between may have
+        // compressed them, so fall back to checking the decompressed body.
+        // The body isn't authenticated yet, so only a small one.
+        let limit = state
+            .max_unverified_decompressed_bytes
+            .min(state.max_decompressed_bytes);
+        if encoding == ContentEncoding::Identity || limit == 0 {
+            return Err(None);
+        }
+        match decompress(encoding, body.clone(), limit).await {
+            Ok(decoded) => match state.verify_signature(&decoded, &signature) {
+                Ok(secret) => Ok((secret, Some(decoded))),
+                Err(_) => Err(None),
+            },
+            Err(DecodeError::TooLarge) => {
+                warn!(
+                    ?encoding,
+                    limit, "compressed payload too large to verify after decompressing"
+                );
+                Err(None)
+            }
+            Err(e) => Err(Some(decode_status(&state, encoding, e))),
+        }
     }
+    .instrument(info_span!("verify_signature"))
+    .await;
+    let (secret, decoded) = match verified {
+        Ok(verified) => verified,
+        Err(Some(status)) => return status.into_response(),
+        Err(None) => {
+            error!(?headers, "failed verifying signature");
+            counter!(metrics::name("failed_verify_signature")).increment(1);
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    };
+    counter!(metrics::name("verified_signatures"), "secret" => secret.name.clone()).increment(1);
+    let body = match decoded {
+        Some(decoded) => decoded,
+        None if encoding == ContentEncoding::Identity => body.to_vec(),
+        None => match decode(&state, encoding, body).await {
+            Ok(decoded) => decoded,
+            Err(status) => return status.into_response(),
+        },
+    };
 
+    if let Some(capture) = &state.capture {
+        capture.record(&headers, &body, &secret.name);
+    }
+
+    let parse_span = info_span!("parse").entered();
     // Now that we've verified the signature, decode the payload as a UTF-8
     // string.
     let body_string = match str::from_utf8(&body) {
         Ok(body_string) => body_string,
         Err(e) => {
             error!("received bad utf-8: {e:?}");
-            counter!("drain_recv_bad_utf8").increment(1);
+            counter!(metrics::name("recv_bad_utf8")).increment(1);
             return StatusCode::NOT_ACCEPTABLE.into_response();
         }
     };
 
-    // Parse the string as JSON.
-    match serde_json::from_str::<types::VercelPayload>(body_string) {
-        Ok(payload) => {
-            debug!("parsed payload, OK");
-            for message in payload.0 {
-                match state.log_queue.send(message) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("failed to queue log message to be sent to outputs: {:?}", e);
-                    }
-                }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok());
+    let format = types::PayloadFormat::detect(content_type, body_string);
+    let format_name = match format {
+        types::PayloadFormat::Json => "json",
+        types::PayloadFormat::Ndjson => "ndjson",
+    };
+    Span::current().record("format", format_name);
+    let (payload, rejected) = match format {
+        types::PayloadFormat::Json => match types::VercelPayload::from_json(body_string) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                error!(bytes = body_string.len(), "failed parsing payload: {e}");
+                debug!(payload = ?body_string, "unparseable payload");
+                counter!(metrics::name("recv_unparseable_payloads")).increment(1);
+                return StatusCode::UNPROCESSABLE_ENTITY.into_response();
             }
+        },
+        types::PayloadFormat::Ndjson => types::VercelPayload::from_ndjson(body_string),
+    };
+
+    if !rejected.is_empty() {
+        let format = format_name;
+        counter!(metrics::name("recv_rejected_messages"), "format" => format)
+            .increment(rejected.len() as u64);
+        let all_rejected = payload.0.is_empty();
+        for rejected in rejected {
+            // The message may hold anything, so it's only logged, and only
+            // the start of it, at debug.
+            warn!(
+                format,
+                position = rejected.position,
+                bytes = rejected.raw.len(),
+                "quarantined malformed message: {}",
+                rejected.error
+            );
+            debug!(
+                position = rejected.position,
+                raw = quarantine::truncate(&rejected.raw, MAX_LOGGED_RAW_BYTES),
+                "malformed message"
+            );
+            state.quarantine.push(rejected);
         }
-        Err(e) => {
-            error!(payload = ?body_string, "failed parsing: {:?}", e);
+        if all_rejected {
+            error!(format, "failed parsing every message in the payload");
             return StatusCode::UNPROCESSABLE_ENTITY.into_response();
         }
     }
 
+    drop(parse_span);
+
+    debug!("parsed payload, OK");
+    histogram!(metrics::name("recv_batch_size")).record(payload.0.len() as f64);
+    Span::current().record("messages", payload.0.len());
+    let _enqueue_span = info_span!("enqueue", messages = payload.0.len()).entered();
+    let trace = TraceContext::current();
+    for mut message in payload.0 {
+        message.tenant = secret.tenant.clone();
+        message.trace = trace.clone();
+        counter!(
+            metrics::name("recv_messages"),
+            "project" => metrics::label("project", Some(message.project())),
+            "source" => metrics::label("source", Some(&message.source)),
+            "environment" => metrics::label("environment", message.environment.as_deref()),
+            "level" => metrics::label("level", message.level.as_deref())
+        )
+        .increment(1);
+        if let Some(requests) = &state.request_metrics {
+            requests.record(&message);
+        }
+        if let Some(seen) = &state.unknown_fields {
+            report_unknown_fields(seen, &message);
+        }
+        // Counted before sending, as the controller may take it off the
+        // queue before `send` returns.
+        state.health.enqueued();
+        if let Err(e) = state.log_queue.send(message) {
+            state.health.dequeued();
+            error!("failed to queue log message to be sent to outputs: {:?}", e);
+        }
+    }
+
     return state.ok_response();
 }
+
+/// Most of a malformed message to log.
+const MAX_LOGGED_RAW_BYTES: usize = 256;
+
+/// Most distinct unknown field names to label metrics with; any others are
+/// counted as `_other`.
+const MAX_UNKNOWN_FIELDS: usize = 256;
+
+fn report_unknown_fields(seen: &Mutex<HashSet<String>>, message: &types::Message) {
+    let mut fields = message.unknown_fields().peekable();
+    if fields.peek().is_none() {
+        return;
+    }
+    let mut seen = seen.lock().unwrap();
+    for (object, field) in fields {
+        let label = if seen.contains(field) {
+            field
+        } else if seen.len() < MAX_UNKNOWN_FIELDS {
+            info!(object, field, "received unknown field from Vercel");
+            seen.insert(String::from(field));
+            field
+        } else {
+            "_other"
+        };
+        counter!(metrics::name("recv_unknown_fields"), "object" => object, "field" => String::from(label))
+            .increment(1);
+    }
+}
+
+#[instrument(skip_all, fields(encoding = encoding.as_str(), bytes = body.len()))]
+async fn decode(
+    state: &types::AppState,
+    encoding: ContentEncoding,
+    body: Bytes,
+) -> Result<Vec<u8>, StatusCode> {
+    decompress(encoding, body, state.max_decompressed_bytes)
+        .await
+        .map_err(|e| decode_status(state, encoding, e))
+}
+
+/// Decompress off the async workers, as a large body takes a while.
+async fn decompress(
+    encoding: ContentEncoding,
+    body: Bytes,
+    limit: usize,
+) -> Result<Vec<u8>, DecodeError> {
+    tokio::task::spawn_blocking(move || encoding.decode(&body, limit))
+        .await
+        .unwrap_or_else(|e| Err(DecodeError::Invalid(std::io::Error::other(e))))
+}
+
+fn decode_status(state: &types::AppState, encoding: ContentEncoding, e: DecodeError) -> StatusCode {
+    match e {
+        DecodeError::TooLarge => {
+            warn!(
+                ?encoding,
+                limit = state.max_decompressed_bytes,
+                "decompressed payload too large"
+            );
+            counter!(metrics::name("recv_too_large")).increment(1);
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+        DecodeError::Invalid(e) => {
+            error!(?encoding, "failed decompressing payload: {e:?}");
+            counter!(metrics::name("recv_bad_encoding")).increment(1);
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Driver and queue health, for the `/ready` and `/admin` endpoints.
+
+use crate::breaker::CircuitState;
+use crate::config::ReadinessConfig;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Default)]
+pub struct Health {
+    thresholds: ReadinessConfig,
+    queue_depth: AtomicUsize,
+    drivers: RwLock<BTreeMap<String, DriverHealth>>,
+    errors: Mutex<VecDeque<DriverError>>,
+}
+
+/// How many driver errors are kept for `/admin/errors`.
+const RECENT_ERRORS: usize = 100;
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DriverHealth {
+    /// Milliseconds since the Unix epoch.
+    pub last_success: Option<i64>,
+    pub consecutive_failures: u64,
+    pub circuit: CircuitState,
+    /// Sends since startup.
+    pub sent: u64,
+    pub failed: u64,
+    /// Messages held back while the circuit is open or the driver is paused.
+    pub buffered: usize,
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DriverError {
+    /// Milliseconds since the Unix epoch.
+    pub time: i64,
+    pub driver: String,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Readiness {
+    pub ready: bool,
+    /// Why the drain isn't ready, if it isn't.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reasons: Vec<String>,
+    pub queue_depth: usize,
+    pub drivers: BTreeMap<String, DriverHealth>,
+}
+
+impl Health {
+    pub fn new(thresholds: ReadinessConfig) -> Self {
+        Self {
+            thresholds,
+            ..Default::default()
+        }
+    }
+
+    /// Count a message about to be queued; call [Health::dequeued] if
+    /// queueing it fails.
+    pub fn enqueued(&self) {
+        self.queue_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Saturating, so a message taken off the queue before it was counted
+    /// can't wrap the depth around.
+    pub fn dequeued(&self) {
+        let _ = self
+            .queue_depth
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                Some(depth.saturating_sub(1))
+            });
+    }
+
+    /// Track exactly these drivers, keeping the history of any that remain.
+    pub fn set_drivers<'a>(&self, names: impl IntoIterator<Item = &'a String>) {
+        let mut drivers = self.drivers.write().unwrap();
+        let mut current = std::mem::take(&mut *drivers);
+        for name in names {
+            let health = current.remove(name).unwrap_or_default();
+            drivers.insert(name.clone(), health);
+        }
+    }
+
+    /// Count a send, keeping the error if it failed.
+    pub fn record(&self, driver: &str, result: &anyhow::Result<()>) {
+        let mut drivers = self.drivers.write().unwrap();
+        let Some(health) = drivers.get_mut(driver) else {
+            return;
+        };
+        let Err(e) = result else {
+            health.last_success = Some(now_millis());
+            health.consecutive_failures = 0;
+            health.sent += 1;
+            return;
+        };
+        health.consecutive_failures += 1;
+        health.failed += 1;
+        drop(drivers);
+
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() >= RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(DriverError {
+            time: now_millis(),
+            driver: driver.to_string(),
+            error: format!("{e:#}"),
+        });
+    }
+
+    pub fn update(&self, driver: &str, update: impl FnOnce(&mut DriverHealth)) {
+        if let Some(health) = self.drivers.write().unwrap().get_mut(driver) {
+            update(health);
+        }
+    }
+
+    pub fn drivers(&self) -> BTreeMap<String, DriverHealth> {
+        self.drivers.read().unwrap().clone()
+    }
+
+    /// The most recent driver errors, oldest first.
+    pub fn errors(&self) -> Vec<DriverError> {
+        self.errors.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        let queue_depth = self.queue_depth.load(Ordering::Relaxed);
+        let drivers = self.drivers();
+
+        let mut reasons = Vec::new();
+        if let Some(max) = self.thresholds.max_queue_depth {
+            if queue_depth > max {
+                reasons.push(format!("queue depth {queue_depth} is over {max}"));
+            }
+        }
+        if let Some(max) = self.thresholds.max_consecutive_failures {
+            for (name, health) in &drivers {
+                if health.consecutive_failures > max {
+                    reasons.push(format!(
+                        "driver {name} failed {} times in a row",
+                        health.consecutive_failures
+                    ));
+                }
+            }
+        }
+
+        Readiness {
+            ready: reasons.is_empty(),
+            reasons,
+            queue_depth,
+            drivers,
+        }
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub(crate) fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_depth_does_not_wrap() {
+        let health = Health::default();
+        // The controller can take a message before the handler counted it.
+        health.dequeued();
+        let readiness = health.readiness();
+        assert_eq!(readiness.queue_depth, 0);
+        assert!(readiness.ready);
+        health.enqueued();
+        assert_eq!(health.readiness().queue_depth, 1);
+    }
+
+    #[test]
+    fn crosses_thresholds() {
+        let health = Health::new(ReadinessConfig {
+            max_queue_depth: Some(1),
+            max_consecutive_failures: Some(2),
+        });
+        let names = [String::from("cloudwatch"), String::from("loki")];
+        health.set_drivers(&names);
+        assert!(health.readiness().ready);
+
+        health.enqueued();
+        health.enqueued();
+        let readiness = health.readiness();
+        assert!(!readiness.ready);
+        assert_eq!(readiness.reasons, ["queue depth 2 is over 1"]);
+        health.dequeued();
+        assert!(health.readiness().ready);
+
+        for _ in 0..3 {
+            health.record("loki", &Err(anyhow::anyhow!("connection refused")));
+        }
+        health.record("cloudwatch", &Ok(()));
+        let readiness = health.readiness();
+        assert_eq!(readiness.reasons, ["driver loki failed 3 times in a row"]);
+        assert!(readiness.drivers["cloudwatch"].last_success.is_some());
+        assert_eq!(readiness.drivers["loki"].failed, 3);
+        let errors = health.errors();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].driver, "loki");
+        assert_eq!(errors[0].error, "connection refused");
+
+        // Dropped drivers no longer count, and kept ones keep their history.
+        health.set_drivers(&names[..1]);
+        let readiness = health.readiness();
+        assert!(readiness.ready);
+        assert!(readiness.drivers["cloudwatch"].last_success.is_some());
+        assert!(!readiness.drivers.contains_key("loki"));
+    }
+}
@@ -1,17 +1,37 @@
+mod access;
+mod admin;
 mod app;
+mod breaker;
+mod capture;
+mod config;
 mod controller;
 mod drivers;
+mod encoding;
 mod handlers;
+mod health;
+mod metrics;
+mod pipeline;
+mod quarantine;
+mod ratelimit;
+mod reload;
+mod replay;
+mod requests;
+mod routing;
+mod telemetry;
+mod tls;
 mod types;
 
-use crate::drivers::*;
-use crate::types::LogDriver;
+use crate::config::{Config, DriverConfig};
+use anyhow::Context;
 use axum::routing::get;
 use axum_prometheus::PrometheusMetricLayerBuilder;
 use clap::Parser;
+use std::{path::PathBuf, sync::Arc};
 use tokio::signal::{unix, unix::SignalKind};
 use tokio::sync::mpsc;
-use tracing::{debug, info, Level};
+use tracing::{info, Level};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
 
 #[cfg(not(any(feature = "cloudwatch", feature = "loki")))]
 compile_error!(
@@ -21,22 +41,55 @@ compile_error!(
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, env = "VERCEL_LOG_DRAIN_LOG_LEVEL", default_value_t = Level::INFO)]
-    log: Level,
-    #[arg(short, long, env = "VERCEL_LOG_DRAIN_IP", default_value = "0.0.0.0")]
-    ip: String,
-    #[arg(short, long, env = "VERCEL_LOG_DRAIN_PORT", default_value_t = 8000)]
-    port: u16,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to a TOML or YAML config file. Flags and env vars override it.
+    #[arg(short, long, env = "VERCEL_LOG_DRAIN_CONFIG", global = true)]
+    config: Option<PathBuf>,
+    /// Validate the configuration and exit without starting.
+    #[arg(long)]
+    check_config: bool,
+
+    #[arg(short, long, env = "VERCEL_LOG_DRAIN_LOG_LEVEL")]
+    log: Option<Level>,
+    #[arg(short, long, env = "VERCEL_LOG_DRAIN_IP")]
+    ip: Option<String>,
+    #[arg(short, long, env = "VERCEL_LOG_DRAIN_PORT")]
+    port: Option<u16>,
+    /// PEM certificate chain; serve HTTPS with `--tls-key`.
+    #[arg(long, env = "VERCEL_LOG_DRAIN_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
 
     #[arg(long, env = "VERCEL_VERIFY")]
-    vercel_verify: String,
+    vercel_verify: Option<String>,
     #[arg(long, env = "VERCEL_SECRET")]
-    vercel_secret: String,
+    vercel_secret: Option<String>,
+    #[arg(long, env = "VERCEL_SIGNATURE_ALGORITHM", value_enum)]
+    signature_algorithm: Option<config::SignatureAlgorithm>,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_UNSIGNED_POLICY", value_enum)]
+    unsigned_policy: Option<config::UnsignedPolicy>,
 
     #[arg(long, env = "VERCEL_LOG_DRAIN_ENABLE_METRICS")]
     enable_metrics: bool,
-    #[arg(long, env = "VERCEL_LOG_DRAIN_METRICS_PREFIX", default_value = "drain")]
-    metrics_prefix: String,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_METRICS_PREFIX")]
+    metrics_prefix: Option<String>,
+
+    #[arg(long, env = "VERCEL_LOG_DRAIN_ADMIN_TOKEN")]
+    admin_token: Option<String>,
+    /// Serve `/health`, `/metrics` and `/admin` here instead of on `--port`.
+    #[arg(long, env = "VERCEL_LOG_DRAIN_ADMIN_LISTEN")]
+    admin_listen: Option<std::net::SocketAddr>,
+
+    /// Write verified request bodies to this directory.
+    #[arg(long, env = "VERCEL_LOG_DRAIN_CAPTURE_DIR")]
+    capture_dir: Option<PathBuf>,
+
+    /// Export traces to this OTLP gRPC endpoint.
+    #[arg(long, env = "VERCEL_LOG_DRAIN_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
 
     #[cfg(feature = "cloudwatch")]
     #[arg(long, env = "VERCEL_LOG_DRAIN_ENABLE_CLOUDWATCH")]
@@ -46,81 +99,384 @@ struct Args {
     #[arg(long, env = "VERCEL_LOG_DRAIN_ENABLE_LOKI")]
     enable_loki: bool,
     #[cfg(feature = "loki")]
-    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_URL", default_value = "")]
-    loki_url: String,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_URL")]
+    loki_url: Option<String>,
+    #[cfg(feature = "loki")]
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_USER")]
+    loki_basic_auth_user: Option<String>,
     #[cfg(feature = "loki")]
-    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_USER", default_value = "")]
-    loki_basic_auth_user: String,
+    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_PASS")]
+    loki_basic_auth_pass: Option<String>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Send files of archived Vercel payloads through the pipeline to the
+    /// configured drivers, then exit.
+    Replay(replay::ReplayArgs),
+}
+
+impl Args {
+    /// Load the config file (if any), apply flag/env overrides and validate.
+    fn load_config(&self) -> anyhow::Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        self.apply_overrides(&mut config)?;
+        match self.command {
+            Some(Command::Replay(_)) => config.validate_for_replay()?,
+            None => config.validate()?,
+        }
+        Ok(config)
+    }
+
+    fn apply_overrides(&self, config: &mut Config) -> anyhow::Result<()> {
+        if self.log.is_some() {
+            config.log = self.log;
+        }
+        if let Some(ip) = &self.ip {
+            config.listen.ip = ip.clone();
+        }
+        if let Some(port) = self.port {
+            config.listen.port = port;
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            match &mut config.listen.tls {
+                Some(tls) => {
+                    tls.cert = cert.clone();
+                    tls.key = key.clone();
+                }
+                None => {
+                    config.listen.tls = Some(config::TlsConfig {
+                        cert: cert.clone(),
+                        key: key.clone(),
+                        http2: false,
+                        watch_interval_secs: 30,
+                    })
+                }
+            }
+        }
+        if self.vercel_verify.is_some() {
+            config.auth.vercel_verify = self.vercel_verify.clone();
+        }
+        if self.vercel_secret.is_some() {
+            config.auth.vercel_secret = self.vercel_secret.clone();
+        }
+        if let Some(algorithm) = self.signature_algorithm {
+            config.auth.signature_algorithm = algorithm;
+        }
+        if let Some(policy) = self.unsigned_policy {
+            config.auth.unsigned.policy = policy;
+        }
+        if self.enable_metrics {
+            config.metrics.enabled = true;
+        }
+        if let Some(prefix) = &self.metrics_prefix {
+            config.metrics.prefix = prefix.clone();
+        }
+        if self.admin_token.is_some() {
+            config.admin.token = self.admin_token.clone();
+        }
+        if self.admin_listen.is_some() {
+            config.admin.listen = self.admin_listen;
+        }
+        if self.capture_dir.is_some() {
+            config.ingest.capture.dir = self.capture_dir.clone();
+        }
+        if self.otlp_endpoint.is_some() {
+            config.tracing.otlp_endpoint = self.otlp_endpoint.clone();
+        }
+
+        #[cfg(feature = "cloudwatch")]
+        if self.enable_cloudwatch {
+            config
+                .drivers
+                .entry(String::from("cloudwatch"))
+                .or_insert_with(|| DriverConfig::Cloudwatch(Default::default()));
+        }
+
+        #[cfg(feature = "loki")]
+        self.apply_loki_overrides(config)?;
+        Ok(())
+    }
+
+    /// Apply the Loki flags to the one Loki driver, whatever it's named, or
+    /// add one named `loki` with `--enable-loki`.
     #[cfg(feature = "loki")]
-    #[arg(long, env = "VERCEL_LOG_DRAIN_LOKI_PASS", default_value = "")]
-    loki_basic_auth_pass: String,
+    fn apply_loki_overrides(&self, config: &mut Config) -> anyhow::Result<()> {
+        let overridden = self.loki_url.is_some()
+            || self.loki_basic_auth_user.is_some()
+            || self.loki_basic_auth_pass.is_some();
+        let mut lokis: Vec<_> = config
+            .drivers
+            .values_mut()
+            .filter_map(|driver| match driver {
+                DriverConfig::Loki(loki) => Some(loki),
+                _ => None,
+            })
+            .collect();
+        let loki = match lokis.len() {
+            0 if self.enable_loki => {
+                let driver = config
+                    .drivers
+                    .entry(String::from("loki"))
+                    .or_insert_with(|| DriverConfig::Loki(Default::default()));
+                match driver {
+                    DriverConfig::Loki(loki) => loki,
+                    driver => anyhow::bail!(
+                        "--enable-loki: driver `loki` is already configured as {}",
+                        driver.kind()
+                    ),
+                }
+            }
+            0 if overridden => anyhow::bail!(
+                "--loki-url, --loki-basic-auth-user and --loki-basic-auth-pass need a Loki driver; \
+                 configure one or pass --enable-loki"
+            ),
+            1 => lokis.remove(0),
+            _ if overridden => anyhow::bail!(
+                "--loki-url, --loki-basic-auth-user and --loki-basic-auth-pass are ambiguous \
+                 with several Loki drivers configured"
+            ),
+            _ => return Ok(()),
+        };
+        if let Some(url) = &self.loki_url {
+            loki.url = url.clone();
+        }
+        if let Some(user) = &self.loki_basic_auth_user {
+            loki.username = user.clone();
+        }
+        if let Some(pass) = &self.loki_basic_auth_pass {
+            loki.password = pass.clone();
+        }
+        Ok(())
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    tracing_subscriber::fmt()
-        .json()
-        .with_max_level(args.log)
+    let args = Arc::new(Args::parse());
+    let config = args.load_config()?;
+
+    if args.check_config {
+        println!(
+            "configuration OK: listening on {}:{}, drivers: {:?}",
+            config.listen.ip,
+            config.listen.port,
+            config.drivers.keys().collect::<Vec<_>>()
+        );
+        return Ok(());
+    }
+
+    // The level can be changed at runtime through `/admin/log-level`.
+    let (log_filter, log_level) = tracing_subscriber::reload::Layer::new(LevelFilter::from_level(
+        config.log.unwrap_or(Level::INFO),
+    ));
+    #[cfg(feature = "otlp")]
+    let otlp = match &config.tracing.otlp_endpoint {
+        Some(endpoint) => Some(telemetry::layer(&config.tracing, endpoint)?),
+        None => None,
+    };
+    #[cfg(not(feature = "otlp"))]
+    let otlp: Option<tracing_subscriber::layer::Identity> = None;
+    // The level only filters logs: exported spans are sampled separately.
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_filter(log_filter),
+        )
+        .with(otlp)
         .init();
 
+    metrics::init(&config.metrics);
+
+    if let Some(Command::Replay(replay)) = &args.command {
+        let replayed = replay::run(&config, replay).await;
+        telemetry::shutdown();
+        return replayed;
+    }
+
     let (tx, rx) = mpsc::unbounded_channel::<types::Message>();
+    let (commands_tx, commands_rx) = mpsc::unbounded_channel::<controller::Command>();
 
-    let mut drivers: Vec<Box<dyn LogDriver>> = Vec::new();
+    let outputs = controller::Outputs {
+        drivers: drivers::from_config(&config.drivers).await?,
+        routes: routing::Routes::new(&config.routes),
+        breaker: config.circuit_breaker.clone(),
+    };
 
-    #[cfg(feature = "cloudwatch")]
-    if args.enable_cloudwatch {
-        let config = aws_config::load_defaults(aws_config::BehaviorVersion::v2024_03_28()).await;
-        let cwl_client = aws_sdk_cloudwatchlogs::Client::new(&config);
-        drivers.push(Box::new(CloudWatchDriver::new(cwl_client)));
-        debug!("added cloudwatch driver");
-    }
+    let pipeline = pipeline::Pipeline::new(&config.pipeline)?;
 
-    #[cfg(feature = "loki")]
-    if args.enable_loki {
-        drivers.push(Box::new(LokiDriver::new(
-            args.loki_url,
-            args.loki_basic_auth_user,
-            args.loki_basic_auth_pass,
-        )));
-        debug!("added loki driver");
-    }
+    let health = Arc::new(health::Health::new(config.readiness.clone()));
 
-    let mut controller = controller::Controller::new(tx.clone(), rx, drivers);
+    let mut controller =
+        controller::Controller::new(tx.clone(), rx, commands_rx, pipeline, outputs)
+            .with_health(health.clone());
 
     controller.init().await?;
 
     tokio::spawn(async move {
         controller.run().await;
     });
-    let state = types::AppState::new(&args.vercel_verify, args.vercel_secret.as_bytes(), tx)?;
 
-    let listen_address = format!("{}:{}", args.ip, args.port);
+    let reloader = Arc::new(reload::Reloader::new(
+        Box::new(move || args.load_config()),
+        commands_tx.clone(),
+    ));
+    tokio::spawn(reload_on_sighup(reloader.clone()));
+
+    // Checked by `Config::validate`.
+    let vercel_verify = config.auth.vercel_verify.clone().unwrap_or_default();
+    let vercel_secret = config.auth.vercel_secret.clone().unwrap_or_default();
+    let secrets = config
+        .auth
+        .all_secrets()
+        .into_iter()
+        .map(|secret| {
+            let vercel_secret = types::VercelSecret::new(
+                &secret.name.unwrap_or_default(),
+                secret.secret.as_bytes(),
+                secret.tenant,
+            );
+            match &secret.vercel_verify {
+                Some(verify) => vercel_secret.with_vercel_verify(verify),
+                None => Ok(vercel_secret),
+            }
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let quarantine = Arc::new(quarantine::Quarantine::new(config.ingest.quarantine_size));
+    let capture = match &config.ingest.capture.dir {
+        Some(dir) => Some(Arc::new(capture::Capture::new(
+            dir,
+            &config.ingest.capture,
+            &config.pipeline.redact,
+        )?)),
+        None => None,
+    };
+    let state = types::AppState::new(&vercel_verify, vercel_secret.as_bytes(), tx)?
+        .with_secrets(secrets)
+        .with_signature_algorithm(config.auth.signature_algorithm)
+        .with_unsigned_policy(
+            config.auth.unsigned.policy,
+            &config.auth.unsigned.rate_limit,
+        )
+        .with_access(access::Access::new(&config.access))
+        .with_max_decompressed_bytes(config.ingest.max_decompressed_bytes)
+        .with_max_unverified_decompressed_bytes(config.ingest.max_unverified_decompressed_bytes)
+        .with_quarantine(quarantine.clone())
+        .with_health(health.clone())
+        .with_unknown_field_reporting(config.ingest.report_unknown_fields)
+        .with_request_metrics(config.metrics.enabled && config.metrics.requests)
+        .with_capture(capture);
+
+    let listen_address = format!("{}:{}", config.listen.ip, config.listen.port);
     let listener = tokio::net::TcpListener::bind(listen_address.clone()).await?;
 
-    let mut app = app::create_app(state);
+    let mut ops = axum::Router::new();
+
+    if let Some(token) = &config.admin.token {
+        let admin_state = admin::AdminState::new(token, reloader)
+            .with_quarantine(quarantine)
+            .with_health(health.clone())
+            .with_commands(commands_tx)
+            .with_log_level(log_level);
+        ops = ops.merge(admin::create_admin_app(admin_state));
+    }
 
-    if args.enable_metrics {
-        let (prometheus_layer, metric_handle) = PrometheusMetricLayerBuilder::new()
-            .with_prefix(args.metrics_prefix)
-            .with_default_metrics()
+    let mut prometheus_layer = None;
+    if config.metrics.enabled {
+        let metric_handle = metrics::install_recorder()?;
+        let (layer, metric_handle) = PrometheusMetricLayerBuilder::new()
+            .with_prefix(config.metrics.prefix.clone())
+            .with_metrics_from_fn(|| metric_handle)
             .build_pair();
-        app = app
-            .route("/metrics", get(|| async move { metric_handle.render() }))
-            .layer(prometheus_layer);
+        ops = ops.route("/metrics", get(|| async move { metric_handle.render() }));
+        prometheus_layer = Some(layer);
+    }
+
+    let mut admin = None;
+    let mut app = match config.admin.listen {
+        Some(admin_address) => {
+            let mut ops = app::create_ops_app(health).merge(ops);
+            if let Some(layer) = &prometheus_layer {
+                ops = ops.layer(layer.clone());
+            }
+            let admin_listener = tokio::net::TcpListener::bind(admin_address).await?;
+            info!("Listening on {} (admin)", admin_address);
+            admin = Some(tokio::spawn(async move {
+                axum::serve(admin_listener, ops)
+                    .with_graceful_shutdown(shutdown_for_signals())
+                    .await
+            }));
+            app::create_ingest_app(state)
+        }
+        None => app::create_app(state).merge(ops),
+    };
+    if let Some(layer) = prometheus_layer {
+        app = app.layer(layer);
     }
 
-    info!("Listening on {}", listen_address);
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
-    )
-    .with_graceful_shutdown(shutdown_for_signals())
-    .await?;
+    let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
+    let public = async {
+        match &config.listen.tls {
+            Some(tls) => {
+                let rustls = tls::rustls_config(tls).await?;
+                let handle = axum_server::Handle::new();
+                tokio::spawn({
+                    let handle = handle.clone();
+                    async move {
+                        shutdown_for_signals().await;
+                        handle.graceful_shutdown(None);
+                    }
+                });
+                info!("Listening on {} (TLS)", listen_address);
+                axum_server::from_tcp_rustls(listener.into_std()?, rustls)
+                    .handle(handle)
+                    .serve(app)
+                    .await?;
+            }
+            None => {
+                info!("Listening on {}", listen_address);
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown_for_signals())
+                    .await?;
+            }
+        }
+        anyhow::Ok(())
+    };
 
+    // Either listener failing stops the drain; on a signal, both shut down
+    // gracefully.
+    match admin {
+        Some(admin) => {
+            tokio::pin!(public);
+            tokio::select! {
+                served = &mut public => served?,
+                served = admin => {
+                    served
+                        .context("admin listener panicked")?
+                        .context("admin listener failed")?;
+                    public.await?;
+                }
+            }
+        }
+        None => public.await?,
+    }
+
+    telemetry::shutdown();
     Ok(())
 }
 
+async fn reload_on_sighup(reloader: Arc<reload::Reloader>) {
+    let mut hangup = unix::signal(SignalKind::hangup()).expect("able to listen for signals");
+    while hangup.recv().await.is_some() {
+        // Failures are logged by the reloader, and the old config stays active.
+        let _ = reloader.reload().await;
+    }
+}
+
 async fn shutdown_for_signals() {
     tokio::select! {
         _interrupt = async {
@@ -143,3 +499,45 @@ async fn shutdown_for_signals() {
         } => {}
     }
 }
+
+#[cfg(all(test, feature = "loki", feature = "cloudwatch"))]
+mod tests {
+    use super::*;
+
+    fn overridden(toml: &str, flags: &[&str]) -> anyhow::Result<Config> {
+        let mut config = Config::from_toml(toml)?;
+        let args = Args::try_parse_from(["vercel-log-drain"].iter().chain(flags))?;
+        args.apply_overrides(&mut config)?;
+        Ok(config)
+    }
+
+    fn loki_url(config: &Config, name: &str) -> Option<String> {
+        match config.drivers.get(name)? {
+            DriverConfig::Loki(loki) => Some(loki.url.clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn applies_loki_flags_to_the_loki_driver() -> anyhow::Result<()> {
+        let url = "http://loki.internal:3100";
+        let config = overridden(
+            "[drivers.grafana]\ntype = \"loki\"\nurl = \"http://old\"",
+            &["--loki-url", url],
+        )?;
+        assert_eq!(loki_url(&config, "grafana").as_deref(), Some(url));
+        assert_eq!(config.drivers.len(), 1);
+
+        let config = overridden("", &["--enable-loki", "--loki-url", url])?;
+        assert_eq!(loki_url(&config, "loki").as_deref(), Some(url));
+
+        // Flags that would do nothing are errors.
+        assert!(overridden("", &["--loki-url", url]).is_err());
+        assert!(overridden(
+            "[drivers.loki]\ntype = \"cloudwatch\"",
+            &["--enable-loki", "--loki-url", url]
+        )
+        .is_err());
+        Ok(())
+    }
+}
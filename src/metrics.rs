@@ -0,0 +1,136 @@
+//! Metric names and label values, shared by everything that records metrics.
+//!
+//! The recorder is global, so the settings are too: set once at startup by
+//! [init], and left at their defaults in tests.
+
+use crate::config::MetricsConfig;
+use anyhow::Result;
+use axum_prometheus::metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use axum_prometheus::utils::SECONDS_DURATION_BUCKETS;
+use std::collections::{HashMap, HashSet};
+use std::sync::{OnceLock, RwLock};
+use tracing::info;
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+static NAMES: OnceLock<RwLock<HashMap<&'static str, &'static str>>> = OnceLock::new();
+static LABELS: OnceLock<RwLock<HashMap<&'static str, HashSet<String>>>> = OnceLock::new();
+
+/// Counted instead of label values past the cap.
+pub const OTHER: &str = "_other";
+
+/// Recorded for a label whose value is missing.
+pub const NONE: &str = "none";
+
+struct Settings {
+    prefix: String,
+    max_label_values: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        let config = MetricsConfig::default();
+        Self {
+            prefix: config.prefix,
+            max_label_values: config.max_label_values,
+        }
+    }
+}
+
+/// Buckets for histograms counting messages, such as `recv_batch_size`.
+const COUNT_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0];
+
+/// Install the Prometheus recorder, with buckets for every histogram;
+/// without them they would be rendered as summaries.
+pub fn install_recorder() -> Result<PrometheusHandle> {
+    Ok(PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix(String::from("_seconds")),
+            SECONDS_DURATION_BUCKETS,
+        )?
+        .set_buckets_for_metric(Matcher::Suffix(String::from("_batch_size")), COUNT_BUCKETS)?
+        .install_recorder()?)
+}
+
+/// Apply the configured prefix and cardinality cap, before any metric is
+/// recorded. Only the first call has any effect, so changes need a restart.
+pub fn init(config: &MetricsConfig) {
+    let _ = SETTINGS.set(Settings {
+        prefix: config.prefix.clone(),
+        max_label_values: config.max_label_values,
+    });
+}
+
+fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+/// The full name of a metric, e.g. `drain_processed_messages` for
+/// `processed_messages`. Each name is built once and kept, as there are only
+/// as many as there are metrics.
+pub fn name(name: &'static str) -> &'static str {
+    let names = NAMES.get_or_init(RwLock::default);
+    if let Some(full) = names.read().unwrap().get(name) {
+        return full;
+    }
+    names
+        .write()
+        .unwrap()
+        .entry(name)
+        .or_insert_with(|| format!("{}_{name}", settings().prefix).leak())
+}
+
+/// A value for a label taken from message data, so that a single label can't
+/// create more than `max_label_values` series; later values are counted as
+/// [OTHER]. The cap is per label, not on a metric's series, which can number
+/// the product of its labels' caps.
+pub fn label(key: &'static str, value: Option<&str>) -> String {
+    let Some(value) = value else {
+        return String::from(NONE);
+    };
+    let labels = LABELS.get_or_init(RwLock::default);
+    if labels
+        .read()
+        .unwrap()
+        .get(key)
+        .is_some_and(|seen| seen.contains(value))
+    {
+        return value.to_string();
+    }
+    let mut labels = labels.write().unwrap();
+    let seen = labels.entry(key).or_default();
+    if seen.contains(value) {
+        return value.to_string();
+    }
+    if seen.len() >= settings().max_label_values {
+        return String::from(OTHER);
+    }
+    if seen.len() + 1 == settings().max_label_values {
+        info!(
+            label = key,
+            "metric label reached its cardinality cap, further values are counted as {OTHER}"
+        );
+    }
+    seen.insert(value.to_string());
+    value.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_label_values() {
+        assert_eq!(name("processed_messages"), "drain_processed_messages");
+        assert_eq!(label("test_caps", None), NONE);
+
+        let max = settings().max_label_values;
+        for i in 0..max {
+            assert_eq!(label("test_caps", Some(&i.to_string())), i.to_string());
+        }
+        assert_eq!(label("test_caps", Some("one too many")), OTHER);
+        // Values seen before the cap keep their own series.
+        assert_eq!(label("test_caps", Some("0")), "0");
+        // Each label has its own cap.
+        assert_eq!(label("test_other", Some("one too many")), "one too many");
+    }
+}
@@ -0,0 +1,320 @@
+use crate::config::{DerivedField, EnrichConfig, StaticFields};
+use crate::types::{self, Message};
+use anyhow::Result;
+use serde_json::Value;
+
+pub struct Enricher {
+    static_fields: Vec<StaticFields>,
+    derived: Vec<DerivedField>,
+    #[cfg(feature = "geoip")]
+    geoip: geoip::GeoIp,
+}
+
+impl Enricher {
+    pub fn new(config: &EnrichConfig) -> Result<Self> {
+        Ok(Self {
+            static_fields: config.static_fields.clone(),
+            derived: config.derived.clone(),
+            #[cfg(feature = "geoip")]
+            geoip: geoip::GeoIp::new(&config.geoip)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        #[cfg(feature = "geoip")]
+        if !self.geoip.is_empty() {
+            return false;
+        }
+        self.static_fields.is_empty() && self.derived.is_empty()
+    }
+
+    pub fn enrich(&self, message: &mut Message) {
+        for entry in &self.static_fields {
+            if entry.matches.matches(message) {
+                for (key, value) in &entry.fields {
+                    message.enrichment.insert(key.clone(), value.clone());
+                }
+            }
+        }
+
+        for derived in &self.derived {
+            let (key, value) = match derived {
+                DerivedField::Latency => ("latencyMs", latency(message)),
+                DerivedField::StatusClass => ("statusClass", status_class(message)),
+            };
+            if let Some(value) = value {
+                message.enrichment.insert(String::from(key), value);
+            }
+        }
+
+        #[cfg(feature = "geoip")]
+        self.geoip.enrich(message);
+    }
+}
+
+fn latency(message: &Message) -> Option<Value> {
+    let proxy = message.proxy.as_ref()?;
+    let latency = message.timestamp - proxy.timestamp;
+    (latency >= 0).then(|| Value::from(latency))
+}
+
+fn status_class(message: &Message) -> Option<Value> {
+    message
+        .status()
+        .and_then(types::status_class)
+        .map(Value::from)
+}
+
+#[cfg(feature = "geoip")]
+mod geoip {
+    use crate::config::GeoIpConfig;
+    use crate::types::Message;
+    use anyhow::{Context, Result};
+    use maxminddb::{geoip2, MaxMindDBError, Reader};
+    use serde_json::Value;
+    use std::net::IpAddr;
+    use tracing::debug;
+
+    pub struct GeoIp {
+        country: Option<Reader<Vec<u8>>>,
+        asn: Option<Reader<Vec<u8>>>,
+    }
+
+    impl GeoIp {
+        pub fn new(config: &GeoIpConfig) -> Result<Self> {
+            let open = |path: &Option<std::path::PathBuf>| {
+                path.as_ref()
+                    .map(|path| {
+                        Reader::open_readfile(path).with_context(|| {
+                            format!("failed to open GeoIP database {}", path.display())
+                        })
+                    })
+                    .transpose()
+            };
+            Ok(Self {
+                country: open(&config.country_database)?,
+                asn: open(&config.asn_database)?,
+            })
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.country.is_none() && self.asn.is_none()
+        }
+
+        pub fn enrich(&self, message: &mut Message) {
+            if self.is_empty() {
+                return;
+            }
+            let Some(ip) = message
+                .proxy
+                .as_ref()
+                .and_then(|proxy| proxy.client_ip.parse::<IpAddr>().ok())
+            else {
+                return;
+            };
+
+            if let Some(reader) = &self.country {
+                match reader.lookup::<geoip2::Country>(ip) {
+                    Ok(record) => {
+                        if let Some(code) = record.country.and_then(|country| country.iso_code) {
+                            message
+                                .enrichment
+                                .insert(String::from("country"), Value::from(code));
+                        }
+                    }
+                    Err(MaxMindDBError::AddressNotFoundError(_)) => {}
+                    Err(e) => debug!(?ip, "country lookup failed: {e:?}"),
+                }
+            }
+
+            if let Some(reader) = &self.asn {
+                match reader.lookup::<geoip2::Asn>(ip) {
+                    Ok(record) => {
+                        if let Some(number) = record.autonomous_system_number {
+                            message
+                                .enrichment
+                                .insert(String::from("asn"), Value::from(number));
+                        }
+                        if let Some(organization) = record.autonomous_system_organization {
+                            message
+                                .enrichment
+                                .insert(String::from("asOrganization"), Value::from(organization));
+                        }
+                    }
+                    Err(MaxMindDBError::AddressNotFoundError(_)) => {}
+                    Err(e) => debug!(?ip, "ASN lookup failed: {e:?}"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::testing::{messages, pipeline_config};
+
+    fn enricher(toml: &str) -> Result<Enricher> {
+        Enricher::new(&pipeline_config(toml)?.enrich)
+    }
+
+    #[test]
+    fn adds_static_fields_for_matching_messages() -> Result<()> {
+        let enricher = enricher(
+            r#"
+            [[pipeline.enrich.static]]
+            fields = { tier = "bronze" }
+
+            [[pipeline.enrich.static]]
+            match = { project_name = "code4rena-com", environment = "production" }
+            fields = { team = "web", tier = "gold" }
+            "#,
+        )?;
+
+        let mut production = messages(include_str!("../fixtures/sample_2.json"))?.remove(0);
+        enricher.enrich(&mut production);
+        assert_eq!(production.enrichment["team"], "web");
+        assert_eq!(production.enrichment["tier"], "gold");
+
+        let mut test = messages(include_str!("../fixtures/test_lambda.json"))?.remove(0);
+        enricher.enrich(&mut test);
+        assert_eq!(test.enrichment.get("team"), None);
+        assert_eq!(test.enrichment["tier"], "bronze");
+
+        let serialized = serde_json::to_value(&production)?;
+        assert_eq!(serialized["enrichment"]["team"], "web");
+        Ok(())
+    }
+
+    #[test]
+    fn derives_latency_and_status_class() -> Result<()> {
+        let enricher = enricher(
+            r#"
+            [pipeline.enrich]
+            derived = ["latency", "status_class"]
+            "#,
+        )?;
+
+        let mut message = messages(include_str!("../fixtures/sample_2.json"))?.remove(0);
+        enricher.enrich(&mut message);
+        assert_eq!(message.enrichment["latencyMs"], 112);
+        assert_eq!(message.enrichment["statusClass"], "2xx");
+
+        // Build logs have neither proxy data nor a status code.
+        let mut build = messages(include_str!("../fixtures/test_build.json"))?.remove(0);
+        enricher.enrich(&mut build);
+        assert!(build.enrichment.is_empty());
+        assert!(serde_json::to_value(&build)?.get("enrichment").is_none());
+        Ok(())
+    }
+
+    /// A MaxMind DB answering for `0.0.0.0/1` only, with both a country and
+    /// an ASN, as a real one can't be checked in.
+    #[cfg(feature = "geoip")]
+    fn geoip_database() -> Vec<u8> {
+        fn string(value: &str) -> Vec<u8> {
+            let mut bytes = match value.len() {
+                len @ 0..=28 => vec![0x40 | len as u8],
+                len => vec![0x40 | 29, (len - 29) as u8],
+            };
+            bytes.extend(value.as_bytes());
+            bytes
+        }
+        fn uint(kind: u8, value: u64, size: usize) -> Vec<u8> {
+            // Types past 7 are extended: the type is in the next byte.
+            let mut bytes = match kind {
+                0..=7 => vec![kind << 5 | size as u8],
+                _ => vec![size as u8, kind - 7],
+            };
+            bytes.extend(&value.to_be_bytes()[8 - size..]);
+            bytes
+        }
+        fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+            let mut bytes = vec![0xe0 | entries.len() as u8];
+            for (key, value) in entries {
+                bytes.extend(string(key));
+                bytes.extend(value);
+            }
+            bytes
+        }
+
+        // One node: the left record points at the data, the right one (equal
+        // to the node count) means not found.
+        let mut database = vec![0, 0, 17, 0, 0, 1];
+        database.extend([0; 16]);
+        database.extend(map(&[
+            ("country", map(&[("iso_code", string("NZ"))])),
+            ("autonomous_system_number", uint(6, 64500, 4)),
+            ("autonomous_system_organization", string("Example Networks")),
+        ]));
+        database.extend(b"\xab\xcd\xefMaxMind.com");
+        database.extend(map(&[
+            ("binary_format_major_version", uint(5, 2, 2)),
+            ("binary_format_minor_version", uint(5, 0, 2)),
+            ("build_epoch", uint(9, 0, 8)),
+            ("database_type", string("Test")),
+            ("description", map(&[])),
+            ("ip_version", uint(5, 4, 2)),
+            ("languages", vec![0, 4]),
+            ("node_count", uint(6, 1, 4)),
+            ("record_size", uint(5, 24, 2)),
+        ]));
+        database
+    }
+
+    #[cfg(feature = "geoip")]
+    #[test]
+    fn looks_up_client_ips() -> Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "vercel-log-drain-geoip-{}.mmdb",
+            std::process::id()
+        ));
+        std::fs::write(&path, geoip_database())?;
+        let enricher = enricher(&format!(
+            r#"
+            [pipeline.enrich.geoip]
+            country_database = "{0}"
+            asn_database = "{0}"
+            "#,
+            path.display()
+        ))?;
+        let enrich = |client_ip: &str| -> Result<_> {
+            let mut message = messages(include_str!("../fixtures/sample_2.json"))?.remove(0);
+            if let Some(proxy) = &mut message.proxy {
+                proxy.client_ip = String::from(client_ip);
+            }
+            enricher.enrich(&mut message);
+            Ok(message.enrichment)
+        };
+
+        let enrichment = enrich("1.2.3.4")?;
+        assert_eq!(enrichment["country"], "NZ");
+        assert_eq!(enrichment["asn"], 64500);
+        assert_eq!(enrichment["asOrganization"], "Example Networks");
+        // Addresses the database doesn't cover, and client IPs that aren't
+        // addresses, are left alone.
+        assert!(enrich("203.0.113.1")?.is_empty());
+        assert!(enrich("not an ip")?.is_empty());
+        assert!(enrich("")?.is_empty());
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "geoip")]
+    #[test]
+    fn fails_on_a_missing_geoip_database() {
+        let error = enricher(
+            r#"
+            [pipeline.enrich.geoip]
+            country_database = "/nonexistent/GeoLite2-Country.mmdb"
+            "#,
+        )
+        .err()
+        .map(|e| e.to_string());
+        assert_eq!(
+            error.as_deref(),
+            Some("failed to open GeoIP database /nonexistent/GeoLite2-Country.mmdb")
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use super::{hash_fraction, lookup};
+use crate::config::{Condition, FilterAction, FilterConfig};
+use crate::metrics;
+use crate::types::Message;
+use axum_prometheus::metrics::counter;
+use serde_json::Value;
+use tracing::error;
+
+pub struct Filters {
+    filters: Vec<Filter>,
+}
+
+struct Filter {
+    name: String,
+    action: FilterAction,
+    rate: f64,
+    sample_by: String,
+    when: Vec<Predicate>,
+}
+
+struct Predicate {
+    field: String,
+    operator: Operator,
+    values: Vec<String>,
+    ignore_case: bool,
+}
+
+enum Operator {
+    Equals,
+    StartsWith,
+    EndsWith,
+    Contains,
+}
+
+impl Filters {
+    pub fn new(configs: &[FilterConfig]) -> Self {
+        let filters = configs
+            .iter()
+            .enumerate()
+            .map(|(index, config)| Filter {
+                name: config
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("filter_{index}")),
+                action: config.action,
+                rate: config.rate.unwrap_or(1.0),
+                sample_by: config.sample_by.clone(),
+                when: config.when.iter().map(Predicate::new).collect(),
+            })
+            .collect();
+        Self { filters }
+    }
+
+    /// Whether the message should continue down the pipeline.
+    pub fn keep(&self, message: &Message) -> bool {
+        if self.filters.is_empty() {
+            return true;
+        }
+        let value = match serde_json::to_value(message) {
+            Ok(value) => value,
+            Err(e) => {
+                error!(
+                    id = message.id,
+                    "failed to serialize message for filtering: {e:?}"
+                );
+                return true;
+            }
+        };
+
+        let Some(filter) = self
+            .filters
+            .iter()
+            .find(|filter| filter.when.iter().all(|predicate| predicate.holds(&value)))
+        else {
+            return true;
+        };
+
+        let keep = match filter.action {
+            FilterAction::Keep => true,
+            FilterAction::Drop => false,
+            FilterAction::Sample => sample(&value, &filter.sample_by, filter.rate),
+        };
+        let name = filter.name.clone();
+        if keep {
+            counter!(metrics::name("filter_kept_messages"), "filter" => name).increment(1);
+        } else {
+            counter!(metrics::name("filter_dropped_messages"), "filter" => name).increment(1);
+        }
+        keep
+    }
+}
+
+impl Predicate {
+    fn new(condition: &Condition) -> Self {
+        let (operator, values) = [
+            (Operator::Equals, &condition.equals),
+            (Operator::StartsWith, &condition.starts_with),
+            (Operator::EndsWith, &condition.ends_with),
+            (Operator::Contains, &condition.contains),
+        ]
+        .into_iter()
+        .find_map(|(operator, values)| values.as_ref().map(|values| (operator, values)))
+        // `Config::validate` ensures there's exactly one operator.
+        .expect("condition without an operator");
+
+        let values = values
+            .0
+            .iter()
+            .map(|value| match condition.ignore_case {
+                true => value.to_lowercase(),
+                false => value.clone(),
+            })
+            .collect();
+        Self {
+            field: condition.field.clone(),
+            operator,
+            values,
+            ignore_case: condition.ignore_case,
+        }
+    }
+
+    fn holds(&self, message: &Value) -> bool {
+        lookup(message, &self.field).is_some_and(|value| self.holds_for(value))
+    }
+
+    fn holds_for(&self, value: &Value) -> bool {
+        let text = match value {
+            Value::String(text) => text.clone(),
+            Value::Number(number) => number.to_string(),
+            Value::Bool(boolean) => boolean.to_string(),
+            Value::Array(values) => return values.iter().any(|value| self.holds_for(value)),
+            Value::Null | Value::Object(_) => return false,
+        };
+        let text = match self.ignore_case {
+            true => text.to_lowercase(),
+            false => text,
+        };
+        self.values.iter().any(|value| match self.operator {
+            Operator::Equals => text == *value,
+            Operator::StartsWith => text.starts_with(value.as_str()),
+            Operator::EndsWith => text.ends_with(value.as_str()),
+            Operator::Contains => text.contains(value.as_str()),
+        })
+    }
+}
+
+/// Deterministically keep `rate` of messages, keyed on the `sample_by` field
+/// (or the message id, if that field is missing).
+fn sample(message: &Value, sample_by: &str, rate: f64) -> bool {
+    let key = lookup(message, sample_by)
+        .or_else(|| lookup(message, "id"))
+        .map(Value::to_string)
+        .unwrap_or_default();
+    hash_fraction(key.as_bytes()) < rate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::testing::{messages, pipeline_config};
+    use anyhow::Result;
+
+    fn filters(toml: &str) -> Result<Filters> {
+        Ok(Filters::new(&pipeline_config(toml)?.filters))
+    }
+
+    #[test]
+    fn drops_by_field_predicates() -> Result<()> {
+        let filters = filters(
+            r#"
+            [[pipeline.filters]]
+            name = "static"
+            action = "drop"
+            when = [{ field = "source", equals = "static" }]
+
+            [[pipeline.filters]]
+            name = "bots"
+            action = "drop"
+            when = [
+                { field = "proxy.path", starts_with = "/robots" },
+                { field = "proxy.userAgent", contains = "bot", ignore_case = true },
+            ]
+            "#,
+        )?;
+
+        for message in messages(include_str!("../fixtures/sample_5.json"))? {
+            assert!(!filters.keep(&message));
+        }
+        for message in messages(include_str!("../fixtures/sample_4.json"))? {
+            assert!(!filters.keep(&message));
+        }
+        for message in messages(include_str!("../fixtures/sample_2.json"))? {
+            assert!(filters.keep(&message));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn first_matching_filter_wins() -> Result<()> {
+        let filters = filters(
+            r#"
+            [[pipeline.filters]]
+            action = "keep"
+            when = [{ field = "proxy.path", ends_with = ".woff2" }]
+
+            [[pipeline.filters]]
+            action = "drop"
+            "#,
+        )?;
+
+        for message in messages(include_str!("../fixtures/sample_5.json"))? {
+            assert!(filters.keep(&message));
+        }
+        for message in messages(include_str!("../fixtures/sample_6.json"))? {
+            assert!(!filters.keep(&message));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn samples_whole_requests() -> Result<()> {
+        let all = filters("[[pipeline.filters]]\naction = \"sample\"\nrate = 1.0")?;
+        let none = filters("[[pipeline.filters]]\naction = \"sample\"\nrate = 0.0")?;
+        let half = filters("[[pipeline.filters]]\naction = \"sample\"\nrate = 0.5")?;
+
+        // Every line in sample_2.json shares one requestId.
+        let messages = messages(include_str!("../fixtures/sample_2.json"))?;
+        let decision = half.keep(&messages[0]);
+        for message in &messages {
+            assert!(all.keep(message));
+            assert!(!none.keep(message));
+            assert_eq!(half.keep(message), decision);
+        }
+        Ok(())
+    }
+}
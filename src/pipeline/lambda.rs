@@ -0,0 +1,129 @@
+//! Pull the Lambda runtime's START/END/REPORT lines into [LambdaLog] fields.
+
+use crate::types::{LambdaLog, Message};
+use anyhow::Result;
+use regex::Regex;
+use serde_json::Value;
+
+// Lines are separated by whitespace, or by (sometimes doubly) escaped `\n` or
+// `\t` sequences, depending on how Vercel forwarded the message.
+const SEP: &str = r"(?:\s|\\+[nt])+";
+
+pub struct LambdaParser {
+    start: Regex,
+    end: Regex,
+    report: Regex,
+}
+
+impl LambdaParser {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            start: Regex::new(&format!(
+                r"START RequestId: ([\w-]+)(?:{SEP}Version: (\$?[\w.]+))?"
+            ))?,
+            end: Regex::new(r"END RequestId: ([\w-]+)")?,
+            report: Regex::new(&format!(
+                r"REPORT RequestId: ([\w-]+){SEP}Duration: ([\d.]+) ms{SEP}Billed Duration: (\d+) ms{SEP}Memory Size: (\d+) MB{SEP}Max Memory Used: (\d+) MB(?:{SEP}Init Duration: ([\d.]+) ms)?"
+            ))?,
+        })
+    }
+
+    pub fn parse(&self, message: &mut Message) {
+        if message.source != "lambda" {
+            return;
+        }
+        let Value::String(text) = &message.message else {
+            return;
+        };
+        if !text.contains("RequestId: ") {
+            return;
+        }
+
+        let mut log = LambdaLog::default();
+        if let Some(captures) = self.start.captures(text) {
+            log.request_id = Some(captures[1].to_owned());
+            log.version = captures.get(2).map(|version| version.as_str().to_owned());
+            log.start = true;
+        }
+        if let Some(captures) = self.end.captures(text) {
+            log.request_id = Some(captures[1].to_owned());
+            log.end = true;
+        }
+        if let Some(captures) = self.report.captures(text) {
+            log.request_id = Some(captures[1].to_owned());
+            log.duration_ms = captures[2].parse().ok();
+            log.billed_duration_ms = captures[3].parse().ok();
+            log.memory_size_mb = captures[4].parse().ok();
+            log.max_memory_used_mb = captures[5].parse().ok();
+            log.init_duration_ms = captures.get(6).and_then(|init| init.as_str().parse().ok());
+            log.cold_start = Some(log.init_duration_ms.is_some());
+        }
+
+        if log.request_id.is_some() {
+            message.lambda = Some(log);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::testing;
+
+    fn messages(data: &str) -> Result<Vec<Message>> {
+        let parser = LambdaParser::new()?;
+        let mut messages = testing::messages(data)?;
+        for message in &mut messages {
+            parser.parse(message);
+        }
+        Ok(messages)
+    }
+
+    #[test]
+    fn parses_report_lines() -> Result<()> {
+        let messages = messages(include_str!("../fixtures/sample_2.json"))?;
+        let log = messages[0].lambda.as_ref().unwrap();
+        assert_eq!(
+            log,
+            &LambdaLog {
+                request_id: Some(String::from("67948f63-24f5-4034-ba85-bbbbab6f1843")),
+                version: None,
+                start: true,
+                end: true,
+                duration_ms: Some(116.0),
+                billed_duration_ms: Some(117),
+                memory_size_mb: Some(1024),
+                max_memory_used_mb: Some(228),
+                init_duration_ms: None,
+                cold_start: Some(false),
+            }
+        );
+
+        // The other lines are application output.
+        assert!(messages[1..].iter().all(|message| message.lambda.is_none()));
+        let serialized = serde_json::to_value(&messages[0])?;
+        assert_eq!(serialized["lambda"]["maxMemoryUsedMb"], 228);
+        assert!(serde_json::to_value(&messages[1])?.get("lambda").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn parses_tab_separated_reports_and_cold_starts() -> Result<()> {
+        let messages = messages(include_str!("../fixtures/test_lambda.json"))?;
+        let log = messages[0].lambda.as_ref().unwrap();
+        assert_eq!(log.version.as_deref(), Some("$LATEST"));
+        assert_eq!(log.duration_ms, Some(1472.2));
+        assert_eq!(log.billed_duration_ms, Some(1473));
+        assert_eq!(log.max_memory_used_mb, Some(147));
+
+        let mut message = messages.into_iter().next().unwrap();
+        message.message = Value::from(
+            "REPORT RequestId: abc-123\tDuration: 2.5 ms\tBilled Duration: 3 ms\tMemory Size: 128 MB\tMax Memory Used: 64 MB\tInit Duration: 250.75 ms\t",
+        );
+        LambdaParser::new()?.parse(&mut message);
+        let log = message.lambda.unwrap();
+        assert_eq!(log.init_duration_ms, Some(250.75));
+        assert_eq!(log.cold_start, Some(true));
+        Ok(())
+    }
+}
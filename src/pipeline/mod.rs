@@ -0,0 +1,71 @@
+//! Stages every [Message] passes through before it is routed to drivers.
+
+mod enrich;
+mod filter;
+mod lambda;
+mod redact;
+#[cfg(test)]
+pub mod testing;
+
+pub use redact::Redactor;
+
+use crate::config::PipelineConfig;
+use crate::types::Message;
+use anyhow::Result;
+use ring::digest;
+use serde_json::Value;
+
+pub struct Pipeline {
+    lambda: Option<lambda::LambdaParser>,
+    filters: filter::Filters,
+    enricher: enrich::Enricher,
+    redactor: redact::Redactor,
+}
+
+impl Pipeline {
+    pub fn new(config: &PipelineConfig) -> Result<Self> {
+        Ok(Self {
+            lambda: match config.parse_lambda {
+                true => Some(lambda::LambdaParser::new()?),
+                false => None,
+            },
+            filters: filter::Filters::new(&config.filters),
+            enricher: enrich::Enricher::new(&config.enrich)?,
+            redactor: redact::Redactor::new(&config.redact)?,
+        })
+    }
+
+    /// Run a message through every stage, returning `None` if it was dropped.
+    pub fn process(&self, mut message: Message) -> Option<Message> {
+        // Parsed first, so filters can use the structured fields.
+        if let Some(parser) = &self.lambda {
+            parser.parse(&mut message);
+        }
+        if !self.filters.keep(&message) {
+            return None;
+        }
+        if !self.enricher.is_empty() {
+            self.enricher.enrich(&mut message);
+        }
+        // Redaction runs last, so earlier stages still see the original data.
+        if !self.redactor.is_empty() {
+            self.redactor.redact(&mut message);
+        }
+        Some(message)
+    }
+}
+
+/// Hash `bytes` to a uniform fraction in [0, 1), for deterministic sampling.
+pub fn hash_fraction(bytes: &[u8]) -> f64 {
+    let hash = digest::digest(&digest::SHA256, bytes);
+    let mut top = [0; 8];
+    top.copy_from_slice(&hash.as_ref()[..8]);
+    // Top 53 bits, as many as an f64 holds exactly.
+    (u64::from_be_bytes(top) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Look up a dotted path (e.g. `proxy.userAgent`) in a serialized message.
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(value, |value, key| value.as_object()?.get(key))
+}
@@ -0,0 +1,15 @@
+//! Helpers for the pipeline stages' tests, and others that need messages.
+
+use crate::config::{Config, PipelineConfig};
+use crate::types::{Message, VercelPayload};
+use anyhow::Result;
+
+/// The `[pipeline]` section of a TOML config.
+pub fn pipeline_config(toml: &str) -> Result<PipelineConfig> {
+    Ok(Config::from_toml(toml)?.pipeline)
+}
+
+/// The messages in a JSON payload, such as a fixture.
+pub fn messages(data: &str) -> Result<Vec<Message>> {
+    Ok(serde_json::from_str::<VercelPayload>(data)?.0)
+}
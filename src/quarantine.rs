@@ -0,0 +1,109 @@
+//! Keep the most recent payload elements that failed to parse, for inspection.
+
+use crate::health::now_millis;
+use crate::types::Rejected;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Default number of rejected elements to keep.
+pub const DEFAULT_CAPACITY: usize = 100;
+
+/// Longest raw element kept, in bytes; anything past it is cut off.
+const MAX_RAW_BYTES: usize = 8 * 1024;
+
+pub struct Quarantine {
+    capacity: usize,
+    entries: Mutex<VecDeque<Entry>>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    /// Milliseconds since the Unix epoch.
+    pub received_at: i64,
+    pub position: usize,
+    pub error: String,
+    pub raw: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+impl Quarantine {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Store a rejected element, evicting the oldest one once full.
+    pub fn push(&self, rejected: Rejected) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut raw = rejected.raw;
+        let truncated = raw.len() > MAX_RAW_BYTES;
+        if truncated {
+            raw.truncate(truncate(&raw, MAX_RAW_BYTES).len());
+        }
+        let entry = Entry {
+            received_at: now_millis(),
+            position: rejected.position,
+            error: rejected.error.to_string(),
+            raw,
+            truncated,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// The stored elements, oldest first.
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl Default for Quarantine {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+/// The first `max` bytes of `s`, or fewer to end on a character boundary.
+pub fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::VercelPayload;
+
+    #[test]
+    fn keeps_the_most_recent_entries() {
+        let quarantine = Quarantine::new(2);
+        let long = format!("[\"{}\"]", "é".repeat(MAX_RAW_BYTES));
+        let (_, rejected) = VercelPayload::from_ndjson(&format!("1\n2\n{long}"));
+        for rejected in rejected {
+            quarantine.push(rejected);
+        }
+
+        let entries = quarantine.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].position, 2);
+        assert_eq!(entries[0].raw, "2");
+        assert!(!entries[0].truncated);
+        assert_eq!(entries[1].position, 3);
+        assert!(entries[1].truncated);
+        assert!(entries[1].raw.len() <= MAX_RAW_BYTES);
+    }
+}
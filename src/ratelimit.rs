@@ -0,0 +1,162 @@
+//! Per-IP token buckets. IPv6 clients are limited per /64, the smallest
+//! network usually assigned to one, so rotating addresses within it doesn't
+//! get a client more buckets.
+
+use crate::config::RateLimitConfig;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Most IPs tracked at once. Buckets are kept in two generations, and a new
+/// one starts when the current one holds half of these; IPs not seen in the
+/// last two generations are forgotten, which leaves them a full bucket.
+const MAX_BUCKETS: usize = 10_000;
+
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Buckets {
+    current: HashMap<IpAddr, Bucket>,
+    previous: HashMap<IpAddr, Bucket>,
+}
+
+impl Buckets {
+    fn get_or_insert(&mut self, ip: IpAddr, new: impl FnOnce() -> Bucket) -> &mut Bucket {
+        if !self.current.contains_key(&ip) {
+            let bucket = self.previous.remove(&ip).unwrap_or_else(new);
+            if self.current.len() >= MAX_BUCKETS / 2 {
+                self.previous = std::mem::take(&mut self.current);
+            }
+            self.current.insert(ip, bucket);
+        }
+        self.current.get_mut(&ip).expect("just inserted")
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.current.len() + self.previous.len()
+    }
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            rate: config.rate,
+            burst: f64::from(config.burst),
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Take a token from `ip`'s bucket, or `false` if it's empty.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert(bucket_key(ip), || Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let tokens = self.refill(bucket, now);
+        bucket.updated = now;
+        if tokens < 1.0 {
+            bucket.tokens = tokens;
+            return false;
+        }
+        bucket.tokens = tokens - 1.0;
+        true
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// The address whose bucket `ip` uses: itself for IPv4, its /64 for IPv6.
+fn bucket_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(ip) => IpAddr::from((u128::from(ip) & !0 << 64).to_be_bytes()),
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn limits_each_ip_separately() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            rate: 1.0,
+            burst: 2,
+        });
+        let start = Instant::now();
+        let (a, b) = (IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2]));
+
+        assert!(limiter.check_at(a, start));
+        assert!(limiter.check_at(a, start));
+        assert!(!limiter.check_at(a, start));
+        assert!(limiter.check_at(b, start));
+
+        // One token back per second, up to the burst.
+        assert!(!limiter.check_at(a, start + Duration::from_millis(500)));
+        assert!(limiter.check_at(a, start + Duration::from_millis(1000)));
+        assert!(!limiter.check_at(a, start + Duration::from_millis(1000)));
+        assert!(limiter.check_at(a, start + Duration::from_secs(60)));
+        assert!(limiter.check_at(a, start + Duration::from_secs(60)));
+        assert!(!limiter.check_at(a, start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn tracks_a_bounded_number_of_ips() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            rate: 0.001,
+            burst: 1,
+        });
+        let now = Instant::now();
+        let limited = IpAddr::from([192, 0, 2, 1]);
+        assert!(limiter.check_at(limited, now));
+
+        // A client spraying IPv6 networks.
+        for i in 0..3 * MAX_BUCKETS as u128 {
+            let ip = IpAddr::from((0x2001_0db8_u128 << 96 | i << 64).to_be_bytes());
+            assert!(limiter.check_at(ip, now));
+            if i % 1000 == 0 {
+                // Seen recently enough to stay limited.
+                assert!(!limiter.check_at(limited, now));
+            }
+            assert!(limiter.buckets.lock().unwrap().len() <= MAX_BUCKETS);
+        }
+    }
+
+    #[test]
+    fn limits_ipv6_clients_per_network() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            rate: 0.001,
+            burst: 1,
+        });
+        let now = Instant::now();
+        let ip = |address: &str| address.parse::<IpAddr>().unwrap();
+
+        assert!(limiter.check_at(ip("2001:db8:1:2::1"), now));
+        assert!(!limiter.check_at(ip("2001:db8:1:2:ffff:ffff:ffff:ffff"), now));
+        assert!(limiter.check_at(ip("2001:db8:1:3::1"), now));
+        // IPv4 clients on a dual-stack socket are still limited per address.
+        assert!(limiter.check_at(ip("::ffff:192.0.2.1"), now));
+        assert!(!limiter.check_at(ip("192.0.2.1"), now));
+        assert!(limiter.check_at(ip("::ffff:192.0.2.2"), now));
+    }
+}
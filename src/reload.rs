@@ -0,0 +1,64 @@
+use crate::config::Config;
+use crate::controller::{Command, Outputs};
+use crate::drivers;
+use crate::pipeline::Pipeline;
+use crate::routing::Routes;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::{mpsc, Mutex};
+use tracing::{error, info};
+
+type LoadConfig = Box<dyn Fn() -> Result<Config> + Send + Sync>;
+
+/// Re-reads the configuration and hands a freshly built pipeline, drivers and
+/// routes to the [crate::controller::Controller].
+///
+/// Anything that fails (parsing, validation or driver init) rejects the whole
+/// reload, and the running drivers are left untouched.
+pub struct Reloader {
+    load_config: LoadConfig,
+    commands: mpsc::UnboundedSender<Command>,
+    // Serializes reloads, so a SIGHUP racing an admin request can't interleave.
+    lock: Mutex<()>,
+}
+
+impl Reloader {
+    pub fn new(load_config: LoadConfig, commands: mpsc::UnboundedSender<Command>) -> Self {
+        Self {
+            load_config,
+            commands,
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub async fn reload(&self) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        info!("reloading configuration");
+
+        let result = self.build().await;
+        match &result {
+            Ok(()) => {
+                info!("configuration reloaded; listener, auth and metrics changes need a restart")
+            }
+            Err(e) => error!("rejected configuration reload, keeping current config: {e:?}"),
+        }
+        result
+    }
+
+    async fn build(&self) -> Result<()> {
+        let config = (self.load_config)()?;
+        let mut drivers = drivers::from_config(&config.drivers).await?;
+        for driver in drivers.values_mut() {
+            driver.init().await?;
+        }
+        let outputs = Outputs {
+            drivers,
+            routes: Routes::new(&config.routes),
+            breaker: config.circuit_breaker.clone(),
+        };
+        let pipeline = Box::new(Pipeline::new(&config.pipeline)?);
+        self.commands
+            .send(Command::Reconfigure { pipeline, outputs })
+            .map_err(|_| anyhow!("controller is not running"))
+    }
+}
@@ -0,0 +1,429 @@
+//! The `replay` subcommand: re-send archived Vercel payloads through the
+//! pipeline, to backfill after an outage or into a newly added driver.
+
+use crate::config::Config;
+use crate::drivers::{self, Drivers};
+use crate::pipeline::Pipeline;
+use crate::routing::Routes;
+use crate::types::{PayloadFormat, VercelPayload};
+use anyhow::{bail, ensure, Context, Result};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::{Interval, MissedTickBehavior};
+use tracing::{info, warn};
+
+/// How often progress is written to the state file, in messages.
+const SAVE_EVERY: usize = 100;
+
+#[derive(Debug, clap::Args)]
+pub struct ReplayArgs {
+    /// Files of Vercel payloads: JSON arrays or NDJSON, optionally gzipped.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// Only send to this configured driver; repeat for several. Defaults to
+    /// every driver.
+    #[arg(long = "driver")]
+    drivers: Vec<String>,
+    /// Send at most this many messages per second.
+    #[arg(long)]
+    rate: Option<f64>,
+    /// Record progress here, and skip what it says was already sent.
+    #[arg(long)]
+    state: Option<PathBuf>,
+}
+
+/// Messages already handled, per file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Progress {
+    files: BTreeMap<PathBuf, FileProgress>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct FileProgress {
+    /// SHA-256 of the file as read, so a changed file is replayed afresh.
+    #[serde(default)]
+    sha256: String,
+    /// Messages in the file, once it has been read.
+    #[serde(default)]
+    messages: Option<usize>,
+    /// Messages each driver has handled, so a resumed replay doesn't resend
+    /// to drivers that already accepted a message.
+    #[serde(default)]
+    drivers: BTreeMap<String, usize>,
+}
+
+impl FileProgress {
+    /// Messages every driver has handled.
+    fn handled<'a>(&self, drivers: impl Iterator<Item = &'a String>) -> usize {
+        drivers
+            .map(|name| self.drivers.get(name).copied().unwrap_or_default())
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    sent: usize,
+    filtered: usize,
+    unrouted: usize,
+    rejected: usize,
+    skipped: usize,
+}
+
+pub async fn run(config: &Config, args: &ReplayArgs) -> Result<()> {
+    if let Some(rate) = args.rate {
+        ensure!(
+            rate.is_finite() && rate > 0.0,
+            "--rate must be a positive number of messages per second"
+        );
+    }
+    let mut configs = config.drivers.clone();
+    if !args.drivers.is_empty() {
+        for name in &args.drivers {
+            if !configs.contains_key(name) {
+                bail!("unknown driver `{name}`");
+            }
+        }
+        configs.retain(|name, _| args.drivers.contains(name));
+    }
+    let mut drivers = drivers::from_config(&configs).await?;
+    for driver in drivers.values_mut() {
+        driver.init().await?;
+    }
+
+    let mut replayer = Replayer::new(
+        Pipeline::new(&config.pipeline)?,
+        Routes::new(&config.routes),
+        drivers,
+    )
+    .with_rate(args.rate);
+    if let Some(state) = &args.state {
+        replayer = replayer.with_state(state)?;
+    }
+
+    for file in &args.files {
+        replayer.replay_file(file).await?;
+    }
+    let stats = &replayer.stats;
+    info!(
+        files = args.files.len(),
+        sent = stats.sent,
+        filtered = stats.filtered,
+        unrouted = stats.unrouted,
+        rejected = stats.rejected,
+        skipped = stats.skipped,
+        "replay finished"
+    );
+    Ok(())
+}
+
+struct Replayer {
+    pipeline: Pipeline,
+    routes: Routes,
+    drivers: Drivers,
+    interval: Option<Interval>,
+    state: Option<PathBuf>,
+    progress: Progress,
+    stats: Stats,
+}
+
+impl Replayer {
+    fn new(pipeline: Pipeline, routes: Routes, drivers: Drivers) -> Self {
+        Self {
+            pipeline,
+            routes,
+            drivers,
+            interval: None,
+            state: None,
+            progress: Progress::default(),
+            stats: Stats::default(),
+        }
+    }
+
+    fn with_rate(mut self, rate: Option<f64>) -> Self {
+        self.interval = rate.map(|rate| {
+            let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / rate));
+            // A rate is a ceiling, so don't catch up after a slow send.
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        self
+    }
+
+    /// Resume from, and record progress to, the state file at `path`.
+    fn with_state(mut self, path: &Path) -> Result<Self> {
+        if path.exists() {
+            let state = std::fs::read_to_string(path)
+                .with_context(|| format!("failed reading replay state {}", path.display()))?;
+            self.progress = serde_json::from_str(&state)
+                .with_context(|| format!("failed parsing replay state {}", path.display()))?;
+        }
+        self.state = Some(path.to_path_buf());
+        Ok(self)
+    }
+
+    async fn replay_file(&mut self, path: &Path) -> Result<()> {
+        let body =
+            std::fs::read(path).with_context(|| format!("failed reading {}", path.display()))?;
+        let sha256 = hex::encode(digest::digest(&digest::SHA256, &body));
+        let mut progress = match self.progress.files.get(path) {
+            Some(progress) if progress.sha256 == sha256 => progress.clone(),
+            Some(_) => {
+                warn!(file = %path.display(), "file changed since it was replayed, starting over");
+                FileProgress::default()
+            }
+            None => FileProgress::default(),
+        };
+        progress.sha256 = sha256;
+        if progress
+            .messages
+            .is_some_and(|messages| progress.handled(self.drivers.keys()) >= messages)
+        {
+            info!(file = %path.display(), "skipping replayed file");
+            return Ok(());
+        }
+
+        let (payload, rejected) = parse_payloads(path, body)?;
+        progress.messages = Some(payload.0.len());
+        let resume_at = progress.handled(self.drivers.keys());
+        self.stats.rejected += rejected;
+        self.stats.skipped += resume_at.min(payload.0.len());
+        info!(
+            file = %path.display(),
+            messages = payload.0.len(),
+            resume_at,
+            "replaying file"
+        );
+
+        for (position, message) in payload.0.into_iter().enumerate().skip(resume_at) {
+            let sent = self.replay_message(position, message, &mut progress).await;
+            if let Err(e) = sent {
+                self.save(path, &progress)?;
+                return Err(e.context(format!(
+                    "failed replaying message {position} of {}",
+                    path.display()
+                )));
+            }
+            if (position + 1) % SAVE_EVERY == 0 {
+                self.save(path, &progress)?;
+            }
+        }
+        self.save(path, &progress)
+    }
+
+    /// Send the message at `position` to the drivers that haven't handled it
+    /// yet, recording each one's progress as it goes.
+    async fn replay_message(
+        &mut self,
+        position: usize,
+        message: crate::types::Message,
+        progress: &mut FileProgress,
+    ) -> Result<()> {
+        let pending = |progress: &FileProgress, name: &String| {
+            progress.drivers.get(name).copied().unwrap_or_default() <= position
+        };
+        let destinations = match self.pipeline.process(message) {
+            None => {
+                self.stats.filtered += 1;
+                None
+            }
+            Some(message) => {
+                let destinations = self.routes.destinations(&message);
+                if self.drivers.keys().any(|name| destinations.includes(name)) {
+                    Some((message, destinations))
+                } else {
+                    self.stats.unrouted += 1;
+                    None
+                }
+            }
+        };
+
+        if let Some((message, destinations)) = destinations {
+            if let Some(interval) = &mut self.interval {
+                interval.tick().await;
+            }
+            for (name, driver) in &mut self.drivers {
+                if !pending(progress, name) {
+                    continue;
+                }
+                if destinations.includes(name) {
+                    driver
+                        .send_log(&message)
+                        .await
+                        .with_context(|| format!("driver `{name}` failed"))?;
+                }
+                progress.drivers.insert(name.clone(), position + 1);
+            }
+            self.stats.sent += 1;
+        }
+        for name in self.drivers.keys() {
+            if pending(progress, name) {
+                progress.drivers.insert(name.clone(), position + 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&mut self, path: &Path, progress: &FileProgress) -> Result<()> {
+        self.progress
+            .files
+            .insert(path.to_path_buf(), progress.clone());
+        let Some(state) = &self.state else {
+            return Ok(());
+        };
+        // Written aside and renamed, so an interrupted write can't lose the
+        // progress already recorded.
+        let partial = state.with_extension("partial");
+        std::fs::write(&partial, serde_json::to_vec_pretty(&self.progress)?)
+            .and_then(|()| std::fs::rename(&partial, state))
+            .with_context(|| format!("failed writing replay state {}", state.display()))
+    }
+}
+
+/// Parse a file of payloads, returning the messages and how many were
+/// malformed.
+fn parse_payloads(path: &Path, mut body: Vec<u8>) -> Result<(VercelPayload, usize)> {
+    if body.starts_with(&[0x1f, 0x8b]) {
+        let mut decoded = Vec::new();
+        flate2::read::MultiGzDecoder::new(body.as_slice())
+            .read_to_end(&mut decoded)
+            .with_context(|| format!("failed decompressing {}", path.display()))?;
+        body = decoded;
+    }
+    let body = std::str::from_utf8(&body)
+        .with_context(|| format!("{} is not valid UTF-8", path.display()))?;
+
+    let (payload, rejected) = match PayloadFormat::detect(None, body) {
+        PayloadFormat::Json => VercelPayload::from_json(body)
+            .with_context(|| format!("failed parsing {}", path.display()))?,
+        PayloadFormat::Ndjson => VercelPayload::from_ndjson(body),
+    };
+    for rejected in &rejected {
+        warn!(
+            file = %path.display(),
+            position = rejected.position,
+            "skipping malformed message: {}",
+            rejected.error
+        );
+    }
+    Ok((payload, rejected.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PipelineConfig;
+    use crate::types::{LogDriver, Message};
+    use async_trait::async_trait;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    /// Fails the send numbered `fail_at`, and remembers the messages it was
+    /// sent.
+    #[derive(Clone, Default)]
+    struct RecordingDriver {
+        fail_at: Option<usize>,
+        attempts: Arc<Mutex<usize>>,
+        sent: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LogDriver for RecordingDriver {
+        async fn init(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn send_log(&mut self, message: &Message) -> Result<()> {
+            let mut attempts = self.attempts.lock().unwrap();
+            *attempts += 1;
+            if Some(*attempts) == self.fail_at {
+                bail!("driver is down");
+            }
+            self.sent.lock().unwrap().push(message.id.clone());
+            Ok(())
+        }
+    }
+
+    fn replayer(drivers: &[(&str, &RecordingDriver)], state: &Path) -> Result<Replayer> {
+        let drivers = drivers
+            .iter()
+            .map(|(name, driver)| {
+                let driver: Box<dyn LogDriver> = Box::new((*driver).clone());
+                (name.to_string(), driver)
+            })
+            .collect();
+        Replayer::new(
+            Pipeline::new(&PipelineConfig::default())?,
+            Routes::default(),
+            drivers,
+        )
+        .with_state(state)
+    }
+
+    #[tokio::test]
+    async fn resumes_after_a_failure() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("vercel-log-drain-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let state = dir.join("state.json");
+        let _ = std::fs::remove_file(&state);
+
+        // The same three messages, as a JSON array and as gzipped NDJSON.
+        let json = include_str!("fixtures/sample_2.json");
+        let (payload, _) = VercelPayload::from_json(json)?;
+        let ids: Vec<_> = payload.0.iter().map(|m| m.id.clone()).collect();
+        let mut ndjson = GzEncoder::new(Vec::new(), Compression::default());
+        for message in &payload.0 {
+            writeln!(ndjson, "{}", serde_json::to_string(message)?)?;
+        }
+        let files = [dir.join("payloads.json"), dir.join("payloads.ndjson.gz")];
+        std::fs::write(&files[0], json)?;
+        std::fs::write(&files[1], ndjson.finish()?)?;
+
+        // `archive` accepts the second message, then `loki` fails it.
+        let archive = RecordingDriver::default();
+        let failing = RecordingDriver {
+            fail_at: Some(2),
+            ..Default::default()
+        };
+        let mut replay = replayer(&[("archive", &archive), ("loki", &failing)], &state)?;
+        assert!(replay.replay_file(&files[0]).await.is_err());
+        assert_eq!(*archive.sent.lock().unwrap(), ids[..2]);
+        assert_eq!(*failing.sent.lock().unwrap(), ids[..1]);
+
+        // A new run picks up at the message that failed, for the driver that
+        // failed it.
+        let loki = RecordingDriver::default();
+        let mut replay = replayer(&[("archive", &archive), ("loki", &loki)], &state)?;
+        for file in &files {
+            replay.replay_file(file).await?;
+        }
+        assert_eq!(replay.stats.skipped, 1);
+        let expected: Vec<_> = ids.iter().chain(&ids).cloned().collect();
+        assert_eq!(*archive.sent.lock().unwrap(), expected);
+        let expected: Vec<_> = ids[1..].iter().chain(&ids).cloned().collect();
+        assert_eq!(*loki.sent.lock().unwrap(), expected);
+
+        // Finished files aren't sent again.
+        let mut replay = replayer(&[("loki", &loki)], &state)?;
+        replay.replay_file(&files[0]).await?;
+        assert_eq!(loki.sent.lock().unwrap().len(), expected.len());
+
+        // Unless they've changed since.
+        let changed = RecordingDriver::default();
+        std::fs::write(&files[0], include_str!("fixtures/sample_1.json"))?;
+        let mut replay = replayer(&[("loki", &changed)], &state)?;
+        replay.replay_file(&files[0]).await?;
+        assert_eq!(replay.stats.skipped, 0);
+        assert!(!changed.sent.lock().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
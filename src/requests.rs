@@ -0,0 +1,147 @@
+//! Edge request metrics, derived from the `proxy` field Vercel attaches to
+//! request logs.
+
+use crate::metrics;
+use crate::types::{self, Message};
+use axum_prometheus::metrics::{counter, histogram};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// How many request IDs are remembered, so a request that logged several
+/// lines is only counted once.
+const REMEMBERED_REQUESTS: usize = 10_000;
+
+#[derive(Default)]
+pub struct RequestMetrics {
+    seen: Mutex<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+/// What is recorded for one request.
+#[derive(Debug, PartialEq)]
+struct Request<'a> {
+    project: &'a str,
+    method: &'a str,
+    status_class: &'static str,
+    region: &'a str,
+    cache: Option<&'a str>,
+    /// From the proxy receiving the request to the first log line about it.
+    latency_secs: Option<f64>,
+}
+
+impl RequestMetrics {
+    pub fn record(&self, message: &Message) {
+        let Some(request) = self.observe(message) else {
+            return;
+        };
+        let region = metrics::label("region", Some(request.region));
+        let cache = metrics::label("cache", request.cache);
+        let project = metrics::label("project", Some(request.project));
+        counter!(
+            metrics::name("edge_requests"),
+            "project" => project.clone(),
+            "method" => metrics::label("method", Some(request.method)),
+            "status_class" => request.status_class,
+            "region" => region.clone(),
+            "cache" => cache.clone()
+        )
+        .increment(1);
+        if let Some(latency) = request.latency_secs {
+            histogram!(
+                metrics::name("edge_request_latency_seconds"),
+                "project" => project,
+                "region" => region,
+                "cache" => cache
+            )
+            .record(latency);
+        }
+    }
+
+    /// The request a message was logged for, unless it was already counted.
+    fn observe<'a>(&self, message: &'a Message) -> Option<Request<'a>> {
+        let proxy = message.proxy.as_ref()?;
+        if let Some(id) = &message.request_id {
+            if !self.seen.lock().unwrap().insert(id) {
+                return None;
+            }
+        }
+        let latency_ms = message.timestamp - proxy.timestamp;
+        Some(Request {
+            project: message.project(),
+            method: &proxy.method,
+            status_class: message
+                .status()
+                .and_then(types::status_class)
+                .unwrap_or(metrics::NONE),
+            region: &proxy.region,
+            cache: proxy.vercel_cache.as_deref(),
+            latency_secs: (latency_ms >= 0).then(|| latency_ms as f64 / 1000.0),
+        })
+    }
+}
+
+impl Seen {
+    /// Remember a request ID, returning whether it's new.
+    fn insert(&mut self, id: &str) -> bool {
+        if self.ids.contains(id) {
+            return false;
+        }
+        if self.order.len() >= REMEMBERED_REQUESTS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        self.ids.insert(id.to_string());
+        self.order.push_back(id.to_string());
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::VercelPayload;
+    use anyhow::Result;
+
+    #[test]
+    fn counts_each_request_once() -> Result<()> {
+        let metrics = RequestMetrics::default();
+        let (payload, _) = VercelPayload::from_json(include_str!("fixtures/sample_2.json"))?;
+        let requests: Vec<_> = payload
+            .0
+            .iter()
+            .filter_map(|message| metrics.observe(message))
+            .collect();
+
+        // All three lines were logged for the same request.
+        assert_eq!(
+            requests,
+            [Request {
+                project: "code4rena-com",
+                method: "GET",
+                status_class: "2xx",
+                region: "bom1",
+                cache: None,
+                latency_secs: Some(0.112),
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn forgets_old_requests() {
+        let mut seen = Seen::default();
+        assert!(seen.insert("first"));
+        assert!(!seen.insert("first"));
+        for i in 0..REMEMBERED_REQUESTS {
+            seen.insert(&i.to_string());
+        }
+        assert!(seen.insert("first"));
+        assert_eq!(seen.ids.len(), REMEMBERED_REQUESTS);
+    }
+}
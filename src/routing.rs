@@ -0,0 +1,207 @@
+//! Decide which drivers receive a [Message].
+
+use crate::config::{MessageMatch, RoutesConfig, Values};
+use crate::metrics;
+use crate::types::Message;
+use axum_prometheus::metrics::counter;
+
+pub struct Routes {
+    rules: Vec<Rule>,
+    default: Option<Vec<String>>,
+}
+
+struct Rule {
+    name: String,
+    matches: MessageMatch,
+    drivers: Vec<String>,
+}
+
+/// The drivers a message should be sent to.
+#[derive(Debug, PartialEq)]
+pub enum Destinations<'a> {
+    All,
+    Only(&'a [String]),
+}
+
+impl Destinations<'_> {
+    pub fn includes(&self, driver: &str) -> bool {
+        match self {
+            Self::All => true,
+            Self::Only(drivers) => drivers.iter().any(|name| name == driver),
+        }
+    }
+}
+
+impl Routes {
+    pub fn new(config: &RoutesConfig) -> Self {
+        let rules = config
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(index, rule)| Rule {
+                name: rule.name.clone().unwrap_or_else(|| format!("rule_{index}")),
+                matches: rule.matches.clone(),
+                drivers: rule.drivers.clone(),
+            })
+            .collect();
+        Self {
+            rules,
+            default: config.default.clone(),
+        }
+    }
+
+    pub fn destinations(&self, message: &Message) -> Destinations<'_> {
+        if let Some(rule) = self.rules.iter().find(|rule| rule.matches.matches(message)) {
+            counter!(metrics::name("routed_messages"), "route" => rule.name.clone()).increment(1);
+            return Destinations::Only(&rule.drivers);
+        }
+
+        counter!(metrics::name("unmatched_messages")).increment(1);
+        match &self.default {
+            Some(drivers) => Destinations::Only(drivers),
+            None => Destinations::All,
+        }
+    }
+}
+
+impl Default for Routes {
+    /// Every message goes to every driver.
+    fn default() -> Self {
+        Self::new(&RoutesConfig::default())
+    }
+}
+
+impl MessageMatch {
+    pub fn matches(&self, message: &Message) -> bool {
+        let conditions = [
+            (&self.project_name, message.project_name.as_deref()),
+            (&self.source, Some(message.source.as_str())),
+            (&self.environment, message.environment.as_deref()),
+            (&self.branch, message.branch.as_deref()),
+            (&self.level, message.level.as_deref()),
+            (&self.output_type, message.output_type.as_deref()),
+            (&self.host, Some(message.host.as_str())),
+            (&self.tenant, message.tenant.as_deref()),
+        ];
+        conditions.into_iter().all(|(values, field)| match values {
+            None => true,
+            Some(values) => field.is_some_and(|field| values.contains(field)),
+        })
+    }
+}
+
+impl Values {
+    pub fn contains(&self, value: &str) -> bool {
+        self.0.iter().any(|v| v == value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::pipeline::testing;
+    use anyhow::Result;
+
+    fn messages() -> Result<Vec<Message>> {
+        let mut messages = Vec::new();
+        for data in [
+            include_str!("fixtures/sample_2.json"),
+            include_str!("fixtures/test_build.json"),
+            include_str!("fixtures/test_static.json"),
+        ] {
+            messages.extend(testing::messages(data)?);
+        }
+        Ok(messages)
+    }
+
+    #[test]
+    fn no_rules_routes_to_all_drivers() -> Result<()> {
+        let routes = Routes::default();
+        for message in messages()? {
+            assert_eq!(routes.destinations(&message), Destinations::All);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn first_matching_rule_wins() -> Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [routes]
+            default = ["cloudwatch"]
+
+            [[routes.rules]]
+            match = { source = "build" }
+            drivers = ["s3"]
+
+            [[routes.rules]]
+            match = { source = "lambda", environment = "production" }
+            drivers = ["loki", "cloudwatch"]
+
+            [[routes.rules]]
+            match = { source = ["lambda", "build"] }
+            drivers = ["never"]
+            "#,
+        )?;
+        let routes = Routes::new(&config.routes);
+
+        for message in messages()? {
+            let destinations = routes.destinations(&message);
+            match message.source.as_str() {
+                "build" => assert_eq!(destinations, Destinations::Only(&[String::from("s3")])),
+                "lambda" => {
+                    assert!(destinations.includes("loki"));
+                    assert!(destinations.includes("cloudwatch"));
+                    assert!(!destinations.includes("never"));
+                }
+                _ => assert_eq!(
+                    destinations,
+                    Destinations::Only(&[String::from("cloudwatch")])
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn routes_by_tenant() -> Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [[routes.rules]]
+            match = { tenant = "team-b" }
+            drivers = ["loki-b"]
+            "#,
+        )?;
+        let routes = Routes::new(&config.routes);
+
+        let mut message = messages()?.remove(0);
+        assert_eq!(routes.destinations(&message), Destinations::All);
+        message.tenant = Some(String::from("team-b"));
+        assert!(routes.destinations(&message).includes("loki-b"));
+        assert!(!routes.destinations(&message).includes("cloudwatch"));
+        Ok(())
+    }
+
+    #[test]
+    fn missing_fields_do_not_match() -> Result<()> {
+        let config = Config::from_toml(
+            r#"
+            [[routes.rules]]
+            match = { project_name = "code4rena-com" }
+            drivers = []
+            "#,
+        )?;
+        let routes = Routes::new(&config.routes);
+
+        // Vercel's test payloads have no projectName.
+        for message in messages()? {
+            let expected = match message.project_name {
+                Some(_) => Destinations::Only(&[]),
+                None => Destinations::All,
+            };
+            assert_eq!(routes.destinations(&message), expected);
+        }
+        Ok(())
+    }
+}
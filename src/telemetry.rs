@@ -0,0 +1,149 @@
+//! OpenTelemetry export of the drain's own traces.
+
+#[cfg(feature = "otlp")]
+use crate::config::TracingConfig;
+#[cfg(feature = "otlp")]
+use anyhow::Result;
+use tracing::Span;
+#[cfg(feature = "otlp")]
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// The trace a message was received in, so its processing can be traced
+/// along with the request, after it has waited in the queue.
+#[derive(Debug, Clone, Default)]
+pub struct TraceContext(#[cfg(feature = "otlp")] opentelemetry::Context);
+
+impl TraceContext {
+    /// The context of the current span.
+    pub fn current() -> Self {
+        #[cfg(feature = "otlp")]
+        return Self(Span::current().context());
+        #[cfg(not(feature = "otlp"))]
+        return Self();
+    }
+
+    /// Make `span` a child of the span this context was taken from, which has
+    /// usually ended by then.
+    pub fn attach(&self, span: &Span) {
+        #[cfg(feature = "otlp")]
+        span.set_parent(self.0.clone());
+        #[cfg(not(feature = "otlp"))]
+        let _ = span;
+    }
+}
+
+/// A layer exporting spans to the configured OTLP (gRPC) endpoint.
+#[cfg(feature = "otlp")]
+pub fn layer<S>(config: &TracingConfig, endpoint: &str) -> Result<impl tracing_subscriber::Layer<S>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::trace::{Config, Sampler};
+    use opentelemetry_sdk::Resource;
+
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(endpoint);
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(config.sample_ratio)));
+    let resource = Resource::new([KeyValue::new("service.name", config.service_name.clone())]);
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(
+            Config::default()
+                .with_sampler(sampler)
+                .with_resource(resource),
+        )
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    opentelemetry::global::set_tracer_provider(provider);
+    Ok(tracer_layer(tracer))
+}
+
+#[cfg(feature = "otlp")]
+fn tracer_layer<S>(tracer: opentelemetry_sdk::trace::Tracer) -> impl tracing_subscriber::Layer<S>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use tracing_subscriber::filter::{LevelFilter, Targets};
+    use tracing_subscriber::Layer;
+
+    // Only the drain's own spans: the exporter's HTTP/2 client is traced too,
+    // and exporting its spans would create more of them.
+    let targets = Targets::new().with_target(env!("CARGO_CRATE_NAME"), LevelFilter::TRACE);
+    tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(targets)
+}
+
+/// Export any spans still buffered.
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[cfg(all(test, feature = "otlp"))]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use tracing::info_span;
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::prelude::*;
+
+    /// Keeps exported spans in memory.
+    #[derive(Debug, Clone, Default)]
+    struct Exported(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Exported {
+        fn export(
+            &mut self,
+            batch: Vec<SpanData>,
+        ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[test]
+    fn exports_spans_whatever_the_log_level() {
+        let exported = Exported::default();
+        let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+            .with_simple_exporter(exported.clone())
+            .build();
+        // As the drain sets it up, with logs turned down to warnings.
+        let (log_filter, _) = tracing_subscriber::reload::Layer::new(LevelFilter::WARN);
+        let subscriber = tracing_subscriber::registry()
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_filter(log_filter),
+            )
+            .with(tracer_layer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let context = info_span!("ingest").in_scope(TraceContext::current);
+            let span = info_span!("process");
+            context.attach(&span);
+            drop(span);
+        });
+
+        let exported = exported.0.lock().unwrap();
+        let names: Vec<_> = exported.iter().map(|span| span.name.as_ref()).collect();
+        assert_eq!(names, ["ingest", "process"]);
+        assert_eq!(
+            exported[1].parent_span_id,
+            exported[0].span_context.span_id()
+        );
+        assert_eq!(
+            exported[1].span_context.trace_id(),
+            exported[0].span_context.trace_id()
+        );
+    }
+}
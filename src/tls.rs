@@ -0,0 +1,162 @@
+//! TLS termination, reloading the certificate when its files change.
+
+use crate::config::TlsConfig;
+use crate::metrics;
+use anyhow::{Context, Result};
+use axum_prometheus::metrics::counter;
+use axum_server::tls_rustls::RustlsConfig;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+/// Load the certificate and key, and watch them for changes if configured.
+pub async fn rustls_config(config: &TlsConfig) -> Result<RustlsConfig> {
+    let rustls = RustlsConfig::from_config(load(config).await?);
+    if config.watch_interval_secs > 0 {
+        let mut watcher = CertWatcher::new(config.clone(), rustls.clone());
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(watcher.config.watch_interval_secs));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                watcher.poll().await;
+            }
+        });
+    }
+    Ok(rustls)
+}
+
+async fn load(config: &TlsConfig) -> Result<Arc<rustls::ServerConfig>> {
+    // Already installed if this is a reload.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let loaded = RustlsConfig::from_pem_file(&config.cert, &config.key)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to load TLS certificate {} and key {}",
+                config.cert.display(),
+                config.key.display()
+            )
+        })?;
+    let mut server = (*loaded.get_inner()).clone();
+    server.alpn_protocols = match config.http2 {
+        true => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        false => vec![b"http/1.1".to_vec()],
+    };
+    Ok(Arc::new(server))
+}
+
+struct CertWatcher {
+    config: TlsConfig,
+    rustls: RustlsConfig,
+    modified: [Option<SystemTime>; 2],
+}
+
+impl CertWatcher {
+    fn new(config: TlsConfig, rustls: RustlsConfig) -> Self {
+        let modified = Self::modified(&config);
+        Self {
+            config,
+            rustls,
+            modified,
+        }
+    }
+
+    /// Modification times of the cert and key (following symlinks, as used by
+    /// Kubernetes secret volumes).
+    fn modified(config: &TlsConfig) -> [Option<SystemTime>; 2] {
+        let modified = |path: &Path| path.metadata().and_then(|meta| meta.modified()).ok();
+        [modified(&config.cert), modified(&config.key)]
+    }
+
+    /// Reload the certificate if either file changed since the last poll.
+    /// Returns whether it was reloaded.
+    async fn poll(&mut self) -> bool {
+        let modified = Self::modified(&self.config);
+        if modified == self.modified {
+            return false;
+        }
+        match load(&self.config).await {
+            Ok(server) => {
+                self.rustls.reload_from_config(server);
+                self.modified = modified;
+                info!(cert = %self.config.cert.display(), "reloaded TLS certificate");
+                counter!(metrics::name("tls_reloads"), "result" => "ok").increment(1);
+                true
+            }
+            Err(e) => {
+                // Keep serving the old certificate, and retry on the next
+                // poll; the key may not have been written yet.
+                error!("failed reloading TLS certificate: {e:#}");
+                counter!(metrics::name("tls_reloads"), "result" => "error").increment(1);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn config(dir: &Path, http2: bool) -> TlsConfig {
+        TlsConfig {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            http2,
+            watch_interval_secs: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn loads_certificate_with_alpn() -> Result<()> {
+        let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures/tls");
+        let http1 = rustls_config(&config(&fixtures, false)).await?;
+        assert_eq!(http1.get_inner().alpn_protocols, [b"http/1.1".to_vec()]);
+        let http2 = rustls_config(&config(&fixtures, true)).await?;
+        assert_eq!(http2.get_inner().alpn_protocols[0], b"h2");
+
+        let missing = config(&fixtures.join("missing"), false);
+        assert!(rustls_config(&missing).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_certificate() -> Result<()> {
+        let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/fixtures/tls");
+        let dir = std::env::temp_dir().join(format!("vercel-log-drain-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        for file in ["cert.pem", "key.pem"] {
+            std::fs::copy(fixtures.join(file), dir.join(file))?;
+        }
+
+        let config = config(&dir, false);
+        let rustls = rustls_config(&config).await?;
+        let mut watcher = CertWatcher::new(config.clone(), rustls.clone());
+        assert!(!watcher.poll().await);
+
+        // A broken key is ignored, and the old certificate kept.
+        let before = rustls.get_inner();
+        std::fs::write(dir.join("key.pem"), "not a key")?;
+        bump_mtime(&dir.join("key.pem"), 1)?;
+        assert!(!watcher.poll().await);
+        assert!(Arc::ptr_eq(&before, &rustls.get_inner()));
+
+        std::fs::copy(fixtures.join("key.pem"), dir.join("key.pem"))?;
+        bump_mtime(&dir.join("key.pem"), 2)?;
+        assert!(watcher.poll().await);
+        assert!(!Arc::ptr_eq(&before, &rustls.get_inner()));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// Make sure the change is seen on filesystems with coarse timestamps.
+    fn bump_mtime(path: &Path, secs: u64) -> std::io::Result<()> {
+        let file = std::fs::File::options().write(true).open(path)?;
+        file.set_modified(SystemTime::now() + Duration::from_secs(secs))
+    }
+}
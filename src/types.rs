@@ -1,19 +1,49 @@
+use crate::access::Access;
+use crate::capture::Capture;
+use crate::config::{RateLimitConfig, SignatureAlgorithm, UnsignedPolicy};
+use crate::health::Health;
+use crate::quarantine::Quarantine;
+use crate::ratelimit::RateLimiter;
+use crate::requests::RequestMetrics;
+use crate::telemetry::TraceContext;
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use axum::{
     body::Body,
-    http::{Response, StatusCode},
+    http::{HeaderValue, Response, StatusCode},
     response::IntoResponse,
 };
 use ring::hmac;
 use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
+use std::collections::HashSet;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Default cap on the size of a decompressed request body.
+pub const DEFAULT_MAX_DECOMPRESSED_BYTES: usize = 32 * 1024 * 1024;
+
+/// Default cap on decompressing a body whose signature isn't verified yet.
+pub const DEFAULT_MAX_UNVERIFIED_DECOMPRESSED_BYTES: usize = 1024 * 1024;
 
 #[derive(Clone)]
 pub struct AppState {
-    vercel_secret: hmac::Key,
+    secrets: Arc<[VercelSecret]>,
+    signature_algorithm: SignatureAlgorithm,
+    /// Rate limits unsigned requests under the `strict` policy; `None` allows
+    /// them all.
+    pub unsigned_limiter: Option<Arc<RateLimiter>>,
+    pub access: Arc<Access>,
+    pub health: Arc<Health>,
     pub log_queue: tokio::sync::mpsc::UnboundedSender<Message>,
     ok_response: Response<()>,
+    pub max_decompressed_bytes: usize,
+    pub max_unverified_decompressed_bytes: usize,
+    pub quarantine: Arc<Quarantine>,
+    /// Unknown field names seen so far, when reporting them is enabled.
+    pub unknown_fields: Option<Arc<Mutex<HashSet<String>>>>,
+    pub request_metrics: Option<Arc<RequestMetrics>>,
+    pub capture: Option<Arc<Capture>>,
 }
 
 impl AppState {
@@ -27,39 +57,299 @@ impl AppState {
             .header("x-vercel-verify", vercel_verify)
             .body(())?;
 
-        let vercel_secret = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, vercel_secret);
-
         Ok(Self {
-            vercel_secret,
+            secrets: Arc::new([VercelSecret::new("default", vercel_secret, None)]),
+            signature_algorithm: SignatureAlgorithm::Auto,
+            unsigned_limiter: None,
+            access: Arc::default(),
+            health: Arc::default(),
             log_queue,
             ok_response,
+            max_decompressed_bytes: DEFAULT_MAX_DECOMPRESSED_BYTES,
+            max_unverified_decompressed_bytes: DEFAULT_MAX_UNVERIFIED_DECOMPRESSED_BYTES,
+            quarantine: Arc::default(),
+            unknown_fields: None,
+            request_metrics: None,
+            capture: None,
         })
     }
 
+    /// Accept requests signed with any of the `secrets`, tried in order,
+    /// instead of just the one passed to [AppState::new]. Their verification
+    /// tokens are answered along with the one passed to [AppState::new], as
+    /// Vercel's verification request doesn't say which team it's from.
+    pub fn with_secrets(mut self, secrets: Vec<VercelSecret>) -> Self {
+        for verify in secrets
+            .iter()
+            .filter_map(|secret| secret.vercel_verify.as_ref())
+        {
+            let headers = self.ok_response.headers_mut();
+            if !headers
+                .get_all("x-vercel-verify")
+                .iter()
+                .any(|v| v == verify)
+            {
+                headers.append("x-vercel-verify", verify.clone());
+            }
+        }
+        self.secrets = secrets.into();
+        self
+    }
+
+    pub fn with_signature_algorithm(mut self, signature_algorithm: SignatureAlgorithm) -> Self {
+        self.signature_algorithm = signature_algorithm;
+        self
+    }
+
+    pub fn with_unsigned_policy(mut self, policy: UnsignedPolicy, limit: &RateLimitConfig) -> Self {
+        self.unsigned_limiter = match policy {
+            UnsignedPolicy::Allow => None,
+            UnsignedPolicy::Strict => Some(Arc::new(RateLimiter::new(limit))),
+        };
+        self
+    }
+
+    pub fn with_access(mut self, access: Access) -> Self {
+        self.access = Arc::new(access);
+        self
+    }
+
+    pub fn with_health(mut self, health: Arc<Health>) -> Self {
+        self.health = health;
+        self
+    }
+
+    pub fn with_max_decompressed_bytes(mut self, max_decompressed_bytes: usize) -> Self {
+        self.max_decompressed_bytes = max_decompressed_bytes;
+        self
+    }
+
+    pub fn with_max_unverified_decompressed_bytes(mut self, max: usize) -> Self {
+        self.max_unverified_decompressed_bytes = max;
+        self
+    }
+
+    pub fn with_quarantine(mut self, quarantine: Arc<Quarantine>) -> Self {
+        self.quarantine = quarantine;
+        self
+    }
+
+    pub fn with_unknown_field_reporting(mut self, enabled: bool) -> Self {
+        self.unknown_fields = enabled.then(Arc::default);
+        self
+    }
+
+    pub fn with_request_metrics(mut self, enabled: bool) -> Self {
+        self.request_metrics = enabled.then(Arc::default);
+        self
+    }
+
+    pub fn with_capture(mut self, capture: Option<Arc<Capture>>) -> Self {
+        self.capture = capture;
+        self
+    }
+
     #[cfg(test)]
-    /// Sign a request with the [AppState]'s Vercel secret.
+    /// Sign a request with the [AppState]'s first Vercel secret.
     ///
     /// This method is only for use in tests.
     pub fn sign_request_for_test_only(&self, body: &[u8]) -> String {
-        let sig = ring::hmac::sign(&self.vercel_secret, body);
-        hex::encode(sig)
+        self.sign_request_with_for_test_only(SignatureAlgorithm::Sha1, body)
+    }
+
+    #[cfg(test)]
+    /// Sign a request with the [AppState]'s first Vercel secret, using the
+    /// given algorithm.
+    ///
+    /// This method is only for use in tests.
+    pub fn sign_request_with_for_test_only(
+        &self,
+        algorithm: SignatureAlgorithm,
+        body: &[u8],
+    ) -> String {
+        hex::encode(ring::hmac::sign(self.secrets[0].key(algorithm), body))
     }
 
-    /// Verify the signature of an incoming request.
-    pub fn verify_signature(&self, body: &[u8], sig_bytes: &[u8]) -> Result<()> {
-        hmac::verify(&self.vercel_secret, body, sig_bytes).map_err(|_| anyhow!("Invalid signature"))
+    /// Parse an `x-vercel-signature` header value.
+    ///
+    /// The algorithm comes from an explicit `sha1=`/`sha256=` prefix, or else
+    /// the digest's length. Returns `None` if the value isn't valid hex of the
+    /// right length, or uses an algorithm other than the configured one.
+    pub fn parse_signature(&self, header: &str) -> Option<Signature> {
+        let (prefixed, hex) = match header.split_once('=') {
+            Some((prefix, hex)) if prefix.eq_ignore_ascii_case("sha1") => {
+                (Some(SignatureAlgorithm::Sha1), hex)
+            }
+            Some((prefix, hex)) if prefix.eq_ignore_ascii_case("sha256") => {
+                (Some(SignatureAlgorithm::Sha256), hex)
+            }
+            _ => (None, header),
+        };
+        let bytes = hex::decode(hex).ok()?;
+        let algorithm = match bytes.len() {
+            20 => SignatureAlgorithm::Sha1,
+            32 => SignatureAlgorithm::Sha256,
+            _ => return None,
+        };
+        if prefixed.is_some_and(|prefixed| prefixed != algorithm) {
+            return None;
+        }
+        match self.signature_algorithm {
+            SignatureAlgorithm::Auto => {}
+            configured if configured != algorithm => return None,
+            _ => {}
+        }
+        Some(Signature { algorithm, bytes })
     }
 
-    /// OK response with `x-vercel-verify` header
+    /// Verify the signature of an incoming request, returning the secret it
+    /// was signed with.
+    pub fn verify_signature(&self, body: &[u8], signature: &Signature) -> Result<&VercelSecret> {
+        self.secrets
+            .iter()
+            .find(|secret| {
+                hmac::verify(secret.key(signature.algorithm), body, &signature.bytes).is_ok()
+            })
+            .ok_or_else(|| anyhow!("Invalid signature"))
+    }
+
+    /// OK response with an `x-vercel-verify` header per verification token
     pub fn ok_response(&self) -> Response<Body> {
         (self.ok_response.clone(), Body::empty()).into_response()
     }
 }
 
+/// A secret shared with Vercel, which requests are signed with.
+#[derive(Clone)]
+pub struct VercelSecret {
+    pub name: String,
+    /// Set as [Message::tenant] on messages signed with this secret.
+    pub tenant: Option<String>,
+    /// The team's `x-vercel-verify` token, if it has its own.
+    vercel_verify: Option<HeaderValue>,
+    sha1: hmac::Key,
+    sha256: hmac::Key,
+}
+
+impl VercelSecret {
+    pub fn new(name: &str, secret: &[u8], tenant: Option<String>) -> Self {
+        Self {
+            name: String::from(name),
+            tenant,
+            vercel_verify: None,
+            sha1: hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret),
+            sha256: hmac::Key::new(hmac::HMAC_SHA256, secret),
+        }
+    }
+
+    pub fn with_vercel_verify(mut self, vercel_verify: &str) -> Result<Self> {
+        self.vercel_verify = Some(HeaderValue::from_str(vercel_verify)?);
+        Ok(self)
+    }
+
+    fn key(&self, algorithm: SignatureAlgorithm) -> &hmac::Key {
+        match algorithm {
+            SignatureAlgorithm::Sha256 => &self.sha256,
+            _ => &self.sha1,
+        }
+    }
+}
+
+/// A parsed `x-vercel-signature`.
+#[derive(Debug)]
+pub struct Signature {
+    /// Either [SignatureAlgorithm::Sha1] or [SignatureAlgorithm::Sha256].
+    pub algorithm: SignatureAlgorithm,
+    bytes: Vec<u8>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct VercelPayload(pub Vec<Message>);
 
-#[derive(Serialize, Deserialize, Debug)]
+impl VercelPayload {
+    /// Parse a JSON array, one [Message] per element.
+    ///
+    /// Only a body that isn't a JSON array is an error; elements that aren't
+    /// valid messages are skipped and returned with their (0-based) index.
+    pub fn from_json(body: &str) -> serde_json::Result<(Self, Vec<Rejected>)> {
+        let elements = serde_json::from_str::<Vec<&RawValue>>(body)?;
+        let mut messages = Vec::with_capacity(elements.len());
+        let mut rejected = Vec::new();
+        for (index, element) in elements.into_iter().enumerate() {
+            match serde_json::from_str::<Message>(element.get()) {
+                Ok(message) => messages.push(message),
+                Err(error) => rejected.push(Rejected::new(index, error, element.get())),
+            }
+        }
+        Ok((Self(messages), rejected))
+    }
+
+    /// Parse newline-delimited JSON, one [Message] per line.
+    ///
+    /// Blank lines are ignored, and malformed lines are skipped and returned
+    /// with their (1-based) line number.
+    pub fn from_ndjson(body: &str) -> (Self, Vec<Rejected>) {
+        let mut messages = Vec::new();
+        let mut rejected = Vec::new();
+        for (index, line) in body.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Message>(line) {
+                Ok(message) => messages.push(message),
+                Err(error) => rejected.push(Rejected::new(index + 1, error, line)),
+            }
+        }
+        (Self(messages), rejected)
+    }
+}
+
+/// A payload element that couldn't be parsed as a [Message].
+#[derive(Debug)]
+pub struct Rejected {
+    /// Array index (JSON) or line number (NDJSON) of the element.
+    pub position: usize,
+    pub error: serde_json::Error,
+    pub raw: String,
+}
+
+impl Rejected {
+    fn new(position: usize, error: serde_json::Error, raw: &str) -> Self {
+        Self {
+            position,
+            error,
+            raw: String::from(raw),
+        }
+    }
+}
+
+/// How a Vercel log drain delivers its payload.
+#[derive(Debug, PartialEq)]
+pub enum PayloadFormat {
+    /// A JSON array of messages.
+    Json,
+    /// One JSON message per line.
+    Ndjson,
+}
+
+impl PayloadFormat {
+    /// Use the `Content-Type` if it names a format, or else sniff the body.
+    pub fn detect(content_type: Option<&str>, body: &str) -> Self {
+        let mime = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|mime| mime.trim().to_ascii_lowercase());
+        match mime.as_deref() {
+            Some("application/x-ndjson" | "application/ndjson" | "application/jsonl") => {
+                Self::Ndjson
+            }
+            Some("application/json") => Self::Json,
+            _ if body.trim_start().starts_with('{') => Self::Ndjson,
+            _ => Self::Json,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Message {
     pub id: String,
@@ -79,13 +369,67 @@ pub struct Message {
     pub path: Option<String>,
     pub entrypoint: Option<String>,
     pub request_id: Option<String>,
-    #[allow(private_interfaces)]
     pub proxy: Option<VercelProxy>,
     pub status_code: Option<i16>,
     pub execution_region: Option<String>,
     pub level: Option<String>,
     pub environment: Option<String>,
     pub branch: Option<String>,
+    /// Parsed from the Lambda runtime's START/END/REPORT lines.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lambda: Option<LambdaLog>,
+    /// Set from the secret the payload was signed with, not sent by Vercel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Fields added by the drain's enrichment stage, not sent by Vercel.
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub enrichment: serde_json::Map<String, serde_json::Value>,
+    /// Fields Vercel sent that aren't listed above, passed through as-is.
+    #[serde(flatten)]
+    pub unknown: serde_json::Map<String, serde_json::Value>,
+    /// The ingest request's trace, not sent to drivers.
+    #[serde(skip)]
+    pub trace: TraceContext,
+}
+
+impl Message {
+    /// The project's name, or its ID if the name isn't set.
+    pub fn project(&self) -> &str {
+        self.project_name.as_deref().unwrap_or(&self.project_id)
+    }
+
+    /// The status the client got: the proxy's, or else the function's.
+    pub fn status(&self) -> Option<isize> {
+        self.proxy
+            .as_ref()
+            .and_then(|proxy| proxy.status_code)
+            .or(self.status_code.map(isize::from))
+    }
+
+    /// Names of the fields Vercel sent that the drain doesn't know about, with
+    /// the object they were found on.
+    pub fn unknown_fields(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        let message = self.unknown.keys().map(|name| ("message", name.as_str()));
+        let proxy = self
+            .proxy
+            .iter()
+            .flat_map(|proxy| proxy.unknown.keys())
+            .map(|name| ("proxy", name.as_str()));
+        message.chain(proxy)
+    }
+}
+
+/// The class of an HTTP status, e.g. `2xx`, or `None` if it isn't one. Vercel
+/// logs -1 for requests that never got a response.
+pub fn status_class(status: isize) -> Option<&'static str> {
+    match status {
+        100..=199 => Some("1xx"),
+        200..=299 => Some("2xx"),
+        300..=399 => Some("3xx"),
+        400..=499 => Some("4xx"),
+        500..=599 => Some("5xx"),
+        _ => None,
+    }
 }
 
 fn deserialize_message_data<'de, D>(deserializer: D) -> Result<serde_json::Value, D::Error>
@@ -106,21 +450,48 @@ where
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-struct VercelProxy {
-    timestamp: i64,
-    method: String,
-    scheme: String,
-    host: String,
+pub struct VercelProxy {
+    pub timestamp: i64,
+    pub method: String,
+    pub scheme: String,
+    pub host: String,
+    pub path: Option<String>,
     #[serde(default)]
-    user_agent: Vec<String>,
-    referer: Option<String>,
-    status_code: Option<isize>,
-    client_ip: String,
-    region: String,
-    cache_id: Option<String>,
-    vercel_cache: Option<String>,
+    pub user_agent: Vec<String>,
+    pub referer: Option<String>,
+    pub status_code: Option<isize>,
+    pub client_ip: String,
+    pub region: String,
+    pub cache_id: Option<String>,
+    pub vercel_cache: Option<String>,
+    /// Fields Vercel sent that aren't listed above, passed through as-is.
+    #[serde(flatten)]
+    pub unknown: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LambdaLog {
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub start: bool,
+    pub end: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub billed_duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory_size_mb: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_memory_used_mb: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub init_duration_ms: Option<f64>,
+    /// Whether the REPORT line included an init duration.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cold_start: Option<bool>,
 }
 
 #[async_trait]
@@ -154,6 +525,107 @@ mod test {
         }
     }
 
+    #[test]
+    fn classifies_statuses() {
+        assert_eq!(status_class(204), Some("2xx"));
+        assert_eq!(status_class(599), Some("5xx"));
+        assert_eq!(status_class(-1), None);
+        assert_eq!(status_class(600), None);
+    }
+
+    #[test]
+    fn keeps_unknown_fields() -> Result<()> {
+        let mut array =
+            serde_json::from_str::<Vec<serde_json::Value>>(include_str!("fixtures/sample_5.json"))?;
+        array[0]["traceId"] = serde_json::json!("abc123");
+        array[0]["edgeType"] = serde_json::json!("middleware");
+        let payload = serde_json::from_value::<VercelPayload>(serde_json::Value::from(array))?;
+
+        let message = &payload.0[0];
+        let mut unknown: Vec<_> = message.unknown_fields().collect();
+        unknown.sort();
+        assert_eq!(
+            unknown,
+            [
+                ("message", "edgeType"),
+                ("message", "traceId"),
+                ("proxy", "pathType"),
+                ("proxy", "vercelId"),
+            ]
+        );
+
+        let serialized = serde_json::to_value(message)?;
+        assert_eq!(serialized["traceId"], "abc123");
+        assert_eq!(serialized["edgeType"], "middleware");
+        assert_eq!(serialized["proxy"]["pathType"], "STATIC");
+        assert!(serialized.get("unknown").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn parses_ndjson_skipping_bad_lines() -> Result<()> {
+        let array =
+            serde_json::from_str::<Vec<serde_json::Value>>(include_str!("fixtures/sample_2.json"))?;
+        let mut lines: Vec<String> = array.iter().map(|m| m.to_string()).collect();
+        lines.insert(1, String::from("{\"id\": \"truncated"));
+        lines.push(String::new());
+        let body = lines.join("\n");
+
+        let (payload, rejected) = VercelPayload::from_ndjson(&body);
+        assert_eq!(payload.0.len(), 3);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].position, 2);
+        assert_eq!(rejected[0].raw, "{\"id\": \"truncated");
+        Ok(())
+    }
+
+    #[test]
+    fn parses_json_skipping_bad_elements() -> Result<()> {
+        let mut array =
+            serde_json::from_str::<Vec<serde_json::Value>>(include_str!("fixtures/sample_2.json"))?;
+        array.insert(1, serde_json::json!({ "id": 1, "source": "lambda" }));
+        array.push(serde_json::json!(42));
+        let body = serde_json::to_string(&array)?;
+
+        let (payload, rejected) = VercelPayload::from_json(&body)?;
+        assert_eq!(payload.0.len(), 3);
+        assert_eq!(
+            rejected
+                .iter()
+                .map(|rejected| (rejected.position, rejected.raw.as_str()))
+                .collect::<Vec<_>>(),
+            [(1, r#"{"id":1,"source":"lambda"}"#), (4, "42")]
+        );
+        assert!(rejected[0].error.to_string().contains("invalid type"));
+
+        // Anything but an array is still an error.
+        assert!(VercelPayload::from_json("{}").is_err());
+        assert!(VercelPayload::from_json("[").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn detects_payload_format() {
+        let cases = [
+            (Some("application/x-ndjson"), "[]", PayloadFormat::Ndjson),
+            (
+                Some("application/json; charset=utf-8"),
+                "{}",
+                PayloadFormat::Json,
+            ),
+            (None, "  \n[{}]", PayloadFormat::Json),
+            (None, "{}\n{}", PayloadFormat::Ndjson),
+            (Some("text/plain"), "{}", PayloadFormat::Ndjson),
+        ];
+        for (content_type, body, expected) in cases {
+            assert_eq!(
+                PayloadFormat::detect(content_type, body),
+                expected,
+                "{content_type:?} {body:?}"
+            );
+        }
+    }
+
     #[test]
     fn parses_structured_messages() -> Result<()> {
         let test_data = [